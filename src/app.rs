@@ -1,93 +1,755 @@
-use std::sync::Arc;
-use winit::{application::ApplicationHandler, dpi::PhysicalSize, event::{self, ElementState, WindowEvent}, event_loop::ActiveEventLoop, keyboard::{KeyCode, PhysicalKey}, window::Window};
+use std::{collections::hash_map, path::PathBuf, sync::Arc, time::{Duration, Instant}};
+use winit::{application::ApplicationHandler, dpi::{LogicalSize, PhysicalPosition, PhysicalSize}, event::{self, ElementState, MouseScrollDelta, WindowEvent}, event_loop::{ActiveEventLoop, ControlFlow, EventLoopProxy}, keyboard::{KeyCode, PhysicalKey}, window::{Fullscreen, Icon, Window, WindowLevel}};
 
 use crate::render;
 
+mod demo;
+mod frame_history;
+mod pane;
+mod settings;
+mod viewport;
+
+/// Builds the UI of the main window every frame
+pub type UiFn = Box<dyn FnMut(&egui::Context)>;
+
+pub enum UserEvent {
+    /// `egui::Context::request_repaint` was called, possibly from another thread
+    RequestRepaint { viewport_id: egui::ViewportId, when: Instant, cumulative_pass_nr: u64 },
+    /// Same as `RequestRepaint`, for the context of the pane added `index`-th by `App::with_pane`
+    RequestPaneRepaint { index: usize, when: Instant, cumulative_pass_nr: u64 },
+}
+
+/// When the main window redraws
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunMode {
+    /// Only on input, animations and `egui::Context::request_repaint` (saves power, e.g. for tools)
+    #[default]
+    Reactive,
+    /// Every frame, as fast as the present mode and `App::with_max_fps` allow (e.g. for games and visualizers)
+    Continuous,
+}
+
+/// Layer whose meshes the renderer keeps while its shapes stay the same (see `App::with_cached_region`)
+struct CachedRegion {
+    /// stands in for the layer's shapes; the same one every frame, so that unchanged frames still compare equal
+    marker: egui::Shape,
+    /// shapes the retained meshes were tessellated from
+    shapes: Vec<egui::epaint::ClippedShape>,
+    /// the retained meshes are out of date
+    stale: bool,
+}
+
 struct AppState {
     zoom_factor: f32,
+    fullscreen: bool,
+    ui: UiFn,
+    /// frame stats, zoom buttons and renderer toggles drawn over the UI (`None` = only over the demo UI)
+    show_overlay: Option<bool>,
+    theme: egui::ThemePreference,
+    /// stats of the previously rendered frame
+    frame_stats: render::FrameStats,
+    /// shapes of the previously rendered frame
+    last_shapes: Vec<egui::epaint::ClippedShape>,
+    /// zoom (or the pixels per point override) changed since the last `update`
+    zoom_changed: bool,
+    /// fixed pixels per point, which ignores both the OS scale factor and the zoom
+    pixels_per_point_override: Option<f32>,
+    /// toggled in the overlay, applied to the renderer before the next frame
+    dithering: bool,
+    /// switched by `App::set_run_mode` or in the overlay
+    run_mode: RunMode,
+    /// the overlay's screenshot button was clicked
+    screenshot_requested: bool,
+    /// FPS and frame time in the top left corner, toggled by F3
+    show_fps: bool,
+    frame_history: frame_history::FrameHistory,
+    cached_regions: egui::IdMap<CachedRegion>,
 }
 impl AppState {
     fn new() -> Self {
         Self {
             zoom_factor: 1.0,
+            fullscreen: false,
+            ui: Box::new(demo::demo_ui()),
+            show_overlay: None,
+            theme: egui::ThemePreference::System,
+            frame_stats: render::FrameStats::default(),
+            last_shapes: Vec::new(),
+            zoom_changed: false,
+            pixels_per_point_override: None,
+            dithering: true,
+            run_mode: RunMode::Reactive,
+            screenshot_requested: false,
+            show_fps: false,
+            frame_history: frame_history::FrameHistory::new(),
+            cached_regions: egui::IdMap::default(),
+        }
+    }
+
+    const MIN_ZOOM: f32 = 0.3;
+    const MAX_ZOOM: f32 = 3.0;
+
+    /// Clamps to `MIN_ZOOM..=MAX_ZOOM` and returns the zoom factor that took effect
+    fn set_zoom(&mut self, zoom_factor: f32) -> f32 {
+        let zoom_factor = match zoom_factor.is_nan() {
+            true => self.zoom_factor,
+            false => zoom_factor.clamp(Self::MIN_ZOOM, Self::MAX_ZOOM),
+        };
+        if zoom_factor != self.zoom_factor {
+            self.zoom_factor = zoom_factor;
+            self.zoom_changed = true;
+        }
+        zoom_factor
+    }
+
+    fn zoom(&self) -> f32 {
+        self.zoom_factor
+    }
+
+    /// Whether the output draws exactly what the previous frame did.
+    /// Paint callbacks may draw anything, so a frame with one (other than a cached region) always counts as changed.
+    fn is_unchanged(&mut self, textures_delta: &egui::TexturesDelta, shapes: &[egui::epaint::ClippedShape]) -> bool {
+        let unchanged = textures_delta.is_empty()
+            && (shapes == self.last_shapes.as_slice())
+            && !self.cached_regions.values().any(|region| region.stale)
+            && !shapes.iter().any(|clipped| matches!(&clipped.shape, egui::Shape::Callback(callback) if !callback.callback.is::<render::RetainedMeshes>()));
+        if !unchanged {
+            self.last_shapes = shapes.to_vec();
+        }
+        unchanged
+    }
+
+    /// Moves the shapes of each cached region out of its layer, leaving a marker that draws the retained meshes instead
+    fn capture_cached_regions(&mut self, cx: &egui::Context) {
+        if self.cached_regions.is_empty() { return };
+
+        cx.graphics_mut(|graphics| {
+            for (id, region) in &mut self.cached_regions {
+                for order in [egui::Order::Background, egui::Order::Middle, egui::Order::Foreground, egui::Order::Tooltip, egui::Order::Debug] {
+                    // layers stay around empty once nothing paints into them
+                    let Some(list) = graphics.get_mut(egui::LayerId::new(order, *id)) else { continue };
+                    if list.is_empty() { continue };
+
+                    let shapes = list.all_entries().cloned().collect::<Vec<_>>();
+                    *list = egui::layers::PaintList::default();
+                    list.add(egui::Rect::EVERYTHING, region.marker.clone());
+                    if shapes != region.shapes {
+                        region.shapes = shapes;
+                        region.stale = true;
+                    }
+                    break;
+                }
+            }
+        });
+    }
+
+    fn invalidate_cached_regions(&mut self) {
+        for region in self.cached_regions.values_mut() {
+            region.stale = true;
         }
     }
 
-    fn update(&mut self, window: &winit::window::Window, state: &mut egui_winit::State) -> (bool, egui::FullOutput) {
+    /// Shapes of the cached regions whose retained meshes are out of date
+    fn take_stale_regions(&mut self) -> Vec<(egui::Id, Vec<egui::epaint::ClippedShape>)> {
+        self.cached_regions.iter_mut()
+            .filter_map(|(id, region)| std::mem::take(&mut region.stale).then(|| (*id, region.shapes.clone())))
+            .collect()
+    }
+
+    /// Window's scale factor times the zoom, unless overridden
+    fn pixels_per_point(&self, window: &winit::window::Window) -> f32 {
+        self.pixels_per_point_override.unwrap_or(window.scale_factor() as f32 * self.zoom_factor)
+    }
+
+    /// `width`: physical width of the column the UI takes (the whole window without split panes)
+    fn update(&mut self, window: &winit::window::Window, state: &mut egui_winit::State, width: u32) -> (bool, egui::FullOutput) {
         let scale_factor = window.scale_factor() as f32;
         let old_zoom = self.zoom_factor;
-        let input = state.take_egui_input(window);
+        let old_pixels_per_point = self.pixels_per_point(window);
+        self.frame_history.on_frame(Instant::now());
+        let mut input = state.take_egui_input(window);
+        pane::narrow_input(&mut input, window, width);
+        // e.g. whether the window is maximized, for `title_bar_interaction`
+        egui_winit::update_viewport_info(input.viewports.entry(input.viewport_id).or_default(), state.egui_ctx(), window, false);
 
         let mut output = state.egui_ctx().run(input, |cx| {
-            egui::Area::new(egui::Id::new("winit + egui + wgpu says hello!"))
-                .show(cx, |ui| {
-                    ui.label("Label!");
-                    if ui.button("boom!").clicked() {
-                        println!("Boom!");
-                    }
+            (self.ui)(cx);
 
-                    ui.separator();
-                    ui.horizontal(|ui| {
-                        ui.label(&format!("ppp: scale ({}) x mag ({}) = {}", scale_factor, old_zoom, scale_factor * old_zoom));
-                    });
-                    ui.horizontal(|ui| {
-                        if ui.button("-").clicked() {
-                            self.zoom_factor = (self.zoom_factor - 0.1).max(0.3);
+            if self.show_fps {
+                self.frame_history.show(cx, &self.frame_stats);
+            }
+            if self.show_overlay.unwrap_or(true) {
+                egui::Area::new(egui::Id::new("zoom controls"))
+                    .anchor(egui::Align2::RIGHT_BOTTOM, [-8.0, -8.0])
+                    .show(cx, |ui| {
+                        let render::FrameStats { draw_calls, vertices, indices, textures_uploaded, textures_allocated, gpu_time_ms } = self.frame_stats;
+                        ui.label(format!("draw calls: {draw_calls}, vertices: {vertices}, indices: {indices}, textures uploaded: {textures_uploaded} (allocated: {textures_allocated})"));
+                        if let Some(gpu_time_ms) = gpu_time_ms {
+                            ui.label(format!("GPU time: {gpu_time_ms:.3} ms"));
                         }
-                        if ui.button("+").clicked() {
-                            self.zoom_factor = (self.zoom_factor + 0.1).min(3.0);
+
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            match self.pixels_per_point_override {
+                                Some(pixels_per_point) => ui.label(format!("ppp: {pixels_per_point} (fixed)")),
+                                None => ui.label(format!("ppp: scale ({}) x mag ({}) = {}", scale_factor, old_zoom, old_pixels_per_point)),
+                            };
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.button("-").clicked() {
+                                self.set_zoom(self.zoom_factor - 0.1);
+                            }
+                            if ui.button("+").clicked() {
+                                self.set_zoom(self.zoom_factor + 0.1);
+                            }
+                        });
+                        ui.checkbox(&mut self.dithering, "Dithering");
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut self.run_mode, RunMode::Reactive, "Reactive");
+                            ui.radio_value(&mut self.run_mode, RunMode::Continuous, "Continuous");
+                        });
+                        if ui.button("Save screenshot").clicked() {
+                            self.screenshot_requested = true;
                         }
-                    });
-                })
-            ;
+                    })
+                ;
+            }
+            self.capture_cached_regions(cx);
         });
 
-        state.egui_ctx().set_pixels_per_point(scale_factor * self.zoom_factor);
-        output.pixels_per_point = scale_factor * self.zoom_factor;
+        let pixels_per_point = self.pixels_per_point(window);
+        state.egui_ctx().set_pixels_per_point(pixels_per_point);
+        output.pixels_per_point = pixels_per_point;
 
-        (self.zoom_factor != old_zoom, output)
+        let scale_changed = std::mem::take(&mut self.zoom_changed) || (pixels_per_point != old_pixels_per_point);
+        // meshes depend on the pixels per point and on the size of the font atlas
+        let font_atlas_replaced = output.textures_delta.set.iter().any(|(id, delta)| (*id == egui::TextureId::default()) && delta.is_whole());
+        if scale_changed || font_atlas_replaced {
+            self.invalidate_cached_regions();
+        }
+        (scale_changed, output)
     }
 }
 
 pub struct App {
     main_window: Option<Arc<Window>>,
-    raw_handle: Option<render::RawWindow>,
     renderer: Option<render::WgpuRenderer>,
     window_state: Option<egui_winit::State>,
+    viewports: egui::ViewportIdMap<viewport::ChildViewport>,
+    next_repaint: Option<Instant>,
     state: AppState,
+    renderer_config: render::RendererConfig,
+    settings_path: Option<PathBuf>,
+    settings: settings::WindowSettings,
+    proxy: Option<EventLoopProxy<UserEvent>>,
+    title: String,
+    icon: Option<egui::IconData>,
+    /// egui's default fonts plus the ones added by `with_font` (`None` = defaults only)
+    fonts: Option<egui::FontDefinitions>,
+    /// anti-aliasing (feathering) and curve quality of the tessellated meshes, for every context
+    tessellation_options: egui::epaint::TessellationOptions,
+    /// size of a window opened without saved settings (`None` = default size fitted to the monitor)
+    inner_size: Option<LogicalSize<u32>>,
+    min_inner_size: Option<LogicalSize<u32>>,
+    max_inner_size: Option<LogicalSize<u32>>,
+    /// title bar and borders drawn by the OS
+    decorations: bool,
+    /// kept across fullscreen toggles; also changed by `ViewportCommand::WindowLevel`
+    window_level: WindowLevel,
+    max_fps: Option<u32>,
+    last_frame: Option<Instant>,
+    /// set by F12 to dump the egui output of the next frame only
+    dump_next_frame: bool,
+    /// forces the next frame to be rendered even if egui's output is unchanged
+    frame_dirty: bool,
+    /// the pending `RedrawRequested` was asked for on egui's behalf (input, repaint requests);
+    /// any other one comes from the OS (e.g. an exposed window), which expects the window to be drawn again
+    egui_redraw_requested: bool,
+    /// the main window is fully hidden by other windows, redraws are paused until it shows again
+    occluded: bool,
+    focused: bool,
+    /// hold scheduled repaints (animations, `request_repaint`) while the main window is unfocused
+    pause_when_unfocused: bool,
+    /// other egui contexts in columns right of the main UI
+    panes: Vec<pane::SplitPane>,
+    /// column under the pointer, which gets the pointer input (0 = main UI)
+    hovered_column: usize,
+    /// column clicked last, which gets the keyboard input
+    keyboard_column: usize,
 }
 impl App {
     const DEFAULT_WIDTH: u32 = 1360;
     const DEFAULT_HEIGHT: u32 = 1024;
+    const DEFAULT_TITLE: &str = "egui-demo";
+    const DEFAULT_MIN_INNER_SIZE: LogicalSize<u32> = LogicalSize::new(320, 240);
+    const SCREENSHOT_POLL_INTERVAL: Duration = Duration::from_millis(5);
 
     pub fn new() -> Self {
         Self {
             main_window: None,
-            raw_handle: None,
             renderer: None,
             window_state: None,
+            viewports: egui::ViewportIdMap::default(),
+            next_repaint: None,
             state: AppState::new(),
+            renderer_config: render::RendererConfig::default(),
+            settings_path: None,
+            settings: settings::WindowSettings {
+                width: Self::DEFAULT_WIDTH,
+                height: Self::DEFAULT_HEIGHT,
+                position: None,
+                zoom_factor: 1.0,
+            },
+            proxy: None,
+            title: Self::DEFAULT_TITLE.to_owned(),
+            icon: None,
+            fonts: None,
+            tessellation_options: egui::epaint::TessellationOptions::default(),
+            inner_size: None,
+            min_inner_size: Some(Self::DEFAULT_MIN_INNER_SIZE),
+            max_inner_size: None,
+            decorations: true,
+            window_level: WindowLevel::Normal,
+            max_fps: None,
+            last_frame: None,
+            dump_next_frame: false,
+            frame_dirty: true,
+            egui_redraw_requested: false,
+            occluded: false,
+            focused: true,
+            pause_when_unfocused: false,
+            panes: Vec::new(),
+            hovered_column: 0,
+            keyboard_column: 0,
         }
     }
 
-    async fn handle_prepare_window_frame(&mut self, event_loop: &ActiveEventLoop) -> Result<(), anyhow::Error> {
-        let w = Arc::new(event_loop.create_window(Window::default_attributes())?);
-        let _ = w.request_inner_size(PhysicalSize::new(Self::DEFAULT_WIDTH, Self::DEFAULT_HEIGHT));
-        let raw_handle = render::RawWindow::create(&w)?;
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
 
-        let screen = render::ScreenDescriptor {
-            pixel_per_point: w.scale_factor() as f32,
-            screen_width: Self::DEFAULT_WIDTH,
-            screen_height: Self::DEFAULT_HEIGHT,
+    /// Window icon from raw RGBA8 pixels (ignored with a warning if the size does not match)
+    pub fn with_icon(mut self, rgba: Vec<u8>, width: u32, height: u32) -> Self {
+        self.icon = Some(egui::IconData { rgba, width, height });
+        self
+    }
+
+    /// Registers a TTF/OTF font under `name` and puts it first in each of `families`,
+    /// e.g. `egui::FontFamily::Monospace` or a custom `egui::FontFamily::Name("code".into())`.
+    /// egui's default fonts stay behind it as fallbacks.
+    pub fn with_font(mut self, name: impl Into<String>, data: Vec<u8>, families: impl IntoIterator<Item = egui::FontFamily>) -> Self {
+        let name = name.into();
+        let fonts = self.fonts.get_or_insert_with(egui::FontDefinitions::default);
+        fonts.font_data.insert(name.clone(), Arc::new(egui::FontData::from_owned(data)));
+        // a new named family falls back to the proportional fonts for missing glyphs
+        let fallback = fonts.families.get(&egui::FontFamily::Proportional).cloned().unwrap_or_default();
+        for family in families {
+            let names = fonts.families.entry(family).or_insert_with(|| fallback.clone());
+            names.retain(|n| *n != name);
+            names.insert(0, name.clone());
+        }
+        self
+    }
+
+    /// How egui turns shapes into meshes, e.g. `feathering: false` for crisp edges in a pixel-art UI
+    /// or a lower `bezier_tolerance` for smoother curves
+    pub fn with_tessellation_options(mut self, options: egui::epaint::TessellationOptions) -> Self {
+        self.tessellation_options = options;
+        self
+    }
+
+    /// Size (in logical pixels) of the window when no saved settings are found.
+    /// `None` opens it at the default size, shrunk to fit 80% of the primary monitor.
+    pub fn with_inner_size(mut self, size: Option<LogicalSize<u32>>) -> Self {
+        self.inner_size = size;
+        self
+    }
+
+    /// Smallest size (in logical pixels) the window can be resized to (`None` = unconstrained)
+    pub fn with_min_inner_size(mut self, size: Option<LogicalSize<u32>>) -> Self {
+        self.min_inner_size = size;
+        self
+    }
+
+    /// Largest size (in logical pixels) the window can be resized to (`None` = unconstrained)
+    pub fn with_max_inner_size(mut self, size: Option<LogicalSize<u32>>) -> Self {
+        self.max_inner_size = size;
+        self
+    }
+
+    /// `false` opens the main window without the OS title bar and borders, for apps that draw their own in egui
+    /// (see [`title_bar_interaction`])
+    pub fn with_decorations(mut self, decorations: bool) -> Self {
+        self.decorations = decorations;
+        self
+    }
+
+    /// See [`App::set_pixels_per_point`]
+    pub fn with_pixels_per_point(mut self, pixels_per_point: Option<f32>) -> Self {
+        self.set_pixels_per_point(pixels_per_point);
+        self
+    }
+
+    /// Keeps the main window above the others (e.g. a HUD or a monitoring panel)
+    pub fn with_always_on_top(mut self, on_top: bool) -> Self {
+        self.window_level = if on_top { WindowLevel::AlwaysOnTop } else { WindowLevel::Normal };
+        self
+    }
+
+    /// Caps how often the main window redraws, independently of the present mode (`None` = uncapped)
+    pub fn with_max_fps(mut self, max_fps: Option<u32>) -> Self {
+        self.max_fps = max_fps.filter(|fps| *fps > 0);
+        self
+    }
+
+    /// Whether the main window redraws every frame or only when needed (default: `RunMode::Reactive`)
+    pub fn with_run_mode(mut self, mode: RunMode) -> Self {
+        self.state.run_mode = mode;
+        self
+    }
+
+    /// Where window size, position and zoom are persisted (`None`, the default, disables persistence)
+    pub fn with_settings_path(mut self, path: Option<PathBuf>) -> Self {
+        self.settings_path = path;
+        self
+    }
+
+    pub fn with_renderer_config(mut self, config: render::RendererConfig) -> Self {
+        self.renderer_config = config;
+        self
+    }
+
+    /// Initial theme (the demo UI can switch it at runtime)
+    pub fn with_theme(mut self, theme: egui::ThemePreference) -> Self {
+        self.state.theme = theme;
+        self
+    }
+
+    /// Lets `egui::Context::request_repaint` wake the event loop while it is waiting
+    pub fn with_event_loop_proxy(mut self, proxy: EventLoopProxy<UserEvent>) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Replaces the demo widgets with the given UI.
+    /// A custom UI is shown without the overlay by default (it used to be drawn on top), `with_overlay(true)` brings it back.
+    pub fn with_ui(mut self, ui: impl FnMut(&egui::Context) + 'static) -> Self {
+        self.state.ui = Box::new(ui);
+        self.state.show_overlay.get_or_insert(false);
+        self
+    }
+
+    /// Whether to draw the frame stats, zoom buttons and renderer toggles on top of the UI.
+    /// Zoom shortcuts keep working without it.
+    pub fn with_overlay(mut self, show: bool) -> Self {
+        self.state.show_overlay = Some(show);
+        self
+    }
+
+    #[deprecated(note = "renamed to `with_overlay`")]
+    pub fn with_zoom_controls(self, show: bool) -> Self {
+        self.with_overlay(show)
+    }
+
+    /// Whether to start with the FPS overlay (FPS, frame time, draw calls and vertices averaged over the last frames).
+    /// F3 toggles it at runtime.
+    pub fn with_fps_overlay(mut self, show: bool) -> Self {
+        self.state.show_fps = show;
+        self
+    }
+
+    /// Keeps the meshes of the layer `id` (e.g. an `egui::Area` with that id holding a fixed toolbar) on the GPU:
+    /// it is only tessellated and uploaded again when its shapes change, the pixels per point change or
+    /// [`App::invalidate_cached_regions`] is called. Worth it for large regions that rarely change.
+    ///
+    /// The layer must not be transformed (e.g. by `egui::Context::set_transform_layer`), and still has to be painted every frame.
+    pub fn with_cached_region(mut self, id: egui::Id) -> Self {
+        self.state.cached_regions.insert(id, CachedRegion {
+            marker: egui::Shape::Callback(render::RetainedMeshes::paint_callback(id)),
+            shapes: Vec::new(),
+            stale: true,
+        });
+        self
+    }
+
+    /// Tessellates and uploads every cached region again on the next frame,
+    /// e.g. after changing a texture they draw through `TextureId::User`
+    pub fn invalidate_cached_regions(&mut self) {
+        self.state.invalidate_cached_regions();
+        if let Some(w) = self.main_window.as_ref() {
+            w.request_redraw();
+        }
+    }
+
+    /// Adds an egui context with its own memory and input, shown in a column right of the main UI (split view).
+    /// The window is split into equal columns; pointer input goes to the column under the pointer.
+    pub fn with_pane(mut self, ui: impl FnMut(&egui::Context) + 'static) -> Self {
+        self.panes.push(pane::SplitPane::new(Box::new(ui)));
+        self
+    }
+
+    /// Stops animating while the main window is in the background; input and resizes still redraw it
+    pub fn with_pause_when_unfocused(mut self, pause: bool) -> Self {
+        self.pause_when_unfocused = pause;
+        self
+    }
+
+    /// Zooms the whole UI (e.g. from a menu or an accessibility tool), clamped to 0.3..=3.0.
+    /// Returns the zoom factor that took effect; the UI is rescaled on the next frame.
+    /// Until the window is created, the zoom saved in the settings file takes precedence.
+    pub fn set_zoom(&mut self, zoom_factor: f32) -> f32 {
+        let zoom_factor = self.state.set_zoom(zoom_factor);
+        if let Some(w) = self.main_window.as_ref() {
+            w.request_redraw();
+        }
+        zoom_factor
+    }
+
+    /// Current zoom factor on top of the window's scale factor
+    pub fn zoom(&self) -> f32 {
+        self.state.zoom()
+    }
+
+    pub fn set_always_on_top(&mut self, on_top: bool) {
+        self.window_level = if on_top { WindowLevel::AlwaysOnTop } else { WindowLevel::Normal };
+        if let Some(w) = self.main_window.as_ref() {
+            w.set_window_level(self.window_level);
+        }
+    }
+
+    pub fn is_always_on_top(&self) -> bool {
+        self.window_level == WindowLevel::AlwaysOnTop
+    }
+
+    /// Physical pixels per egui point the main window uses (`None` until the window is created)
+    pub fn pixels_per_point(&self) -> Option<f32> {
+        self.main_window.as_ref().map(|w| self.state.pixels_per_point(w))
+    }
+
+    /// Fixes the pixels per point (e.g. for reproducible screenshots or a kiosk display), `None` goes back to the default.
+    /// While set, the window no longer follows the OS scale factor (e.g. when moved to another monitor), and zooming has no effect.
+    /// Values that are not positive are ignored.
+    pub fn set_pixels_per_point(&mut self, pixels_per_point: Option<f32>) {
+        self.state.pixels_per_point_override = pixels_per_point.filter(|ppp| ppp.is_finite() && (*ppp > 0.0));
+        self.state.zoom_changed = true;
+        if let Some(w) = self.main_window.as_ref() {
+            w.request_redraw();
+        }
+    }
+
+    /// Switches between redrawing on demand and every frame, e.g. while a simulation is running
+    pub fn set_run_mode(&mut self, mode: RunMode) {
+        self.state.run_mode = mode;
+        if let Some(w) = self.main_window.as_ref() {
+            w.request_redraw();
+        }
+    }
+
+    pub fn run_mode(&self) -> RunMode {
+        self.state.run_mode
+    }
+
+    /// See [`App::with_tessellation_options`]; the main UI and the panes are tessellated with them from the next frame
+    pub fn set_tessellation_options(&mut self, options: egui::epaint::TessellationOptions) {
+        self.tessellation_options = options;
+        let contexts = self.window_state.iter().chain(self.panes.iter().filter_map(|pane| pane.state()))
+            .map(|state| state.egui_ctx())
+        ;
+        for ctx in contexts {
+            ctx.tessellation_options_mut(|o| *o = options);
+        }
+        // the shapes stay the same, but not their meshes
+        self.state.invalidate_cached_regions();
+        self.frame_dirty = true;
+        if let Some(w) = self.main_window.as_ref() {
+            w.request_redraw();
+        }
+    }
+
+    pub fn tessellation_options(&self) -> egui::epaint::TessellationOptions {
+        self.tessellation_options
+    }
+
+    /// Renders the main window right away instead of waiting for the event loop to deliver `RedrawRequested`,
+    /// and waits until the GPU has finished the frame, e.g. to show a "Saving..." state before a blocking operation.
+    ///
+    /// Meant for code driving the app between events, such as an `ApplicationHandler` wrapping it.
+    /// It must not be called while a frame is in progress (e.g. from the UI closure, which runs inside one);
+    /// the UI asks for the same with [`request_redraw_now`].
+    /// The frame is drawn even if nothing changed or the FPS cap would hold it back; a pending `RedrawRequested`
+    /// is still delivered afterwards.
+    pub fn redraw_now(&mut self, event_loop: &ActiveEventLoop) {
+        self.frame_dirty = true;
+        self.last_frame = None;
+        self.handle_redraw(event_loop);
+        self.wait_for_gpu();
+    }
+
+    fn wait_for_gpu(&self) {
+        if let Some(r) = self.renderer.as_ref() && let Err(err) = r.device().poll(wgpu::PollType::wait_indefinitely()) {
+            log::warn!("Unable to wait for the frame (reason: {err})");
+        }
+    }
+
+    /// Whether the main window's UI called [`request_redraw_now`] during the last frame
+    fn take_redraw_now_request(&self) -> bool {
+        self.window_state.as_ref()
+            .and_then(|s| s.egui_ctx().data_mut(|d| d.remove_temp::<bool>(redraw_now_id())))
+            .unwrap_or(false)
+    }
+
+    /// Scheduled repaints of the main window wait while this is true
+    fn is_repaint_paused(&self) -> bool {
+        self.occluded || (self.pause_when_unfocused && !self.focused)
+    }
+
+    /// egui context with the app's theme, fonts and tessellation options
+    fn make_context(&self) -> egui::Context {
+        let ctx = egui::Context::default();
+        ctx.set_theme(self.state.theme);
+        ctx.tessellation_options_mut(|o| *o = self.tessellation_options);
+        if let Some(fonts) = self.fonts.clone() {
+            ctx.set_fonts(fonts);
+        }
+        ctx
+    }
+
+    fn column_state_mut(&mut self, column: usize) -> Option<&mut egui_winit::State> {
+        match column {
+            0 => self.window_state.as_mut(),
+            _ => self.panes.get_mut(column - 1).and_then(pane::SplitPane::state_mut),
+        }
+    }
+
+    /// Passes a main window event on to egui: pointer input to the column under the pointer,
+    /// keyboard and IME to the column clicked last, anything else (focus, theme, modifiers) to every column.
+    /// Returns the response and whether a widget has keyboard focus in the column that took a key.
+    fn forward_window_event(&mut self, window: &Window, event: &WindowEvent) -> Option<(egui_winit::EventResponse, bool)> {
+        let count = 1 + self.panes.len();
+        let window_width = window.inner_size().width;
+
+        let target = match event {
+            WindowEvent::CursorMoved { device_id, position } => {
+                let column = pane::column_at(position.x, count, window_width);
+                if column != self.hovered_column {
+                    let left = std::mem::replace(&mut self.hovered_column, column);
+                    if let Some(state) = self.column_state_mut(left) {
+                        let _ = state.on_window_event(window, &WindowEvent::CursorLeft { device_id: *device_id });
+                    }
+                }
+                Some(column)
+            }
+            WindowEvent::Touch(touch) => Some(pane::column_at(touch.location.x, count, window_width)),
+            WindowEvent::MouseInput { state: ElementState::Pressed, .. } => {
+                self.keyboard_column = self.hovered_column;
+                Some(self.hovered_column)
+            }
+            WindowEvent::MouseInput { .. } | WindowEvent::MouseWheel { .. } | WindowEvent::CursorLeft { .. } | WindowEvent::DroppedFile(_)
+            | WindowEvent::PinchGesture { .. } | WindowEvent::PanGesture { .. } | WindowEvent::RotationGesture { .. } | WindowEvent::DoubleTapGesture { .. } => Some(self.hovered_column),
+            WindowEvent::KeyboardInput { .. } | WindowEvent::Ime(_) => Some(self.keyboard_column),
+            _ => None,
         };
 
-        let mut renderer = render::WgpuRenderer::create(screen.screen_width, screen.screen_height, &raw_handle).await?;
-        renderer.request_resize(&screen);
+        match target {
+            Some(column) => {
+                let (origin_x, _) = pane::column(column, count, window_width);
+                let event = pane::translate_event(event, origin_x);
+                let state = self.column_state_mut(column)?;
+                let response = state.on_window_event(window, &event);
+                Some((response, state.egui_ctx().memory(|mem| mem.focused().is_some())))
+            }
+            None => {
+                let state = self.window_state.as_mut()?;
+                let mut response = state.on_window_event(window, event);
+                let has_focus = state.egui_ctx().memory(|mem| mem.focused().is_some());
+                for state in self.panes.iter_mut().filter_map(pane::SplitPane::state_mut) {
+                    let pane_response = state.on_window_event(window, event);
+                    response.repaint |= pane_response.repaint;
+                    response.consumed |= pane_response.consumed;
+                }
+                Some((response, has_focus))
+            }
+        }
+    }
 
-        self.renderer = Some(renderer);
+    /// Sizes a window opened for the first time (configured size, or the default one within 80% of the monitor)
+    /// and centers it on the primary monitor
+    fn fit_to_monitor(&mut self, event_loop: &ActiveEventLoop) {
+        const MAX_MONITOR_SHARE: f64 = 0.8;
+
+        // Wayland has no notion of a primary monitor
+        let Some(monitor) = event_loop.primary_monitor().or_else(|| event_loop.available_monitors().next()) else {
+            if let Some(size) = self.inner_size {
+                (self.settings.width, self.settings.height) = (size.width.max(1), size.height.max(1));
+            }
+            return;
+        };
+        let monitor_size = monitor.size();
+
+        let size = match self.inner_size {
+            Some(size) => size.to_physical::<u32>(monitor.scale_factor()),
+            None => PhysicalSize::new(
+                self.settings.width.min((monitor_size.width as f64 * MAX_MONITOR_SHARE) as u32),
+                self.settings.height.min((monitor_size.height as f64 * MAX_MONITOR_SHARE) as u32),
+            ),
+        };
+        let position = monitor.position();
+        self.settings.width = size.width.max(1);
+        self.settings.height = size.height.max(1);
+        self.settings.position = Some((
+            position.x + (monitor_size.width.saturating_sub(size.width) / 2) as i32,
+            position.y + (monitor_size.height.saturating_sub(size.height) / 2) as i32,
+        ));
+    }
+
+    async fn handle_prepare_window_frame(&mut self, event_loop: &ActiveEventLoop) -> Result<(), anyhow::Error> {
+        match self.settings_path.as_deref().and_then(settings::WindowSettings::load) {
+            Some(settings) => self.settings = settings,
+            None => self.fit_to_monitor(event_loop),
+        }
+        self.state.zoom_factor = self.settings.zoom_factor.clamp(AppState::MIN_ZOOM, AppState::MAX_ZOOM);
+
+        let mut attrs = Window::default_attributes()
+            .with_title(&self.title)
+            .with_transparent(self.renderer_config.transparent)
+            .with_decorations(self.decorations)
+            .with_window_level(self.window_level)
+        ;
+        if let Some(size) = self.min_inner_size {
+            attrs = attrs.with_min_inner_size(size);
+        }
+        if let Some(size) = self.max_inner_size {
+            attrs = attrs.with_max_inner_size(size);
+        }
+        if let Some(egui::IconData { rgba, width, height }) = self.icon.clone() {
+            match Icon::from_rgba(rgba, width, height) {
+                Ok(icon) => attrs = attrs.with_window_icon(Some(icon)),
+                Err(err) => log::warn!("Unable to set the window icon (reason: {err})"),
+            }
+        }
+        if let Some((x, y)) = self.settings.position {
+            attrs = attrs.with_position(PhysicalPosition::new(x, y));
+        }
+        let w = Arc::new(event_loop.create_window(attrs)?);
+        // the OS may not grant the requested size (e.g. tiling window managers), so go by what the window reports
+        let size = w.request_inner_size(PhysicalSize::new(self.settings.width, self.settings.height))
+            .unwrap_or_else(|| w.inner_size())
+        ;
+
+        let ctx = self.make_context();
+        // deferred viewports get their own OS windows
+        ctx.set_embed_viewports(false);
+
+        if let Some(proxy) = self.proxy.clone() {
+            ctx.set_request_repaint_callback(move |info| {
+                let when = Instant::now() + info.delay;
+                let _ = proxy.send_event(UserEvent::RequestRepaint { viewport_id: info.viewport_id, when, cumulative_pass_nr: info.current_cumulative_pass_nr });
+            });
+        }
 
         self.window_state = Some(egui_winit::State::new(
-            egui::Context::default(),
+            ctx,
             egui::viewport::ViewportId::ROOT,
             &w,
             Some(w.scale_factor() as f32),
@@ -95,65 +757,389 @@ impl App {
             None
         ));
 
-        self.main_window.get_or_insert_with(|| w);
-        self.raw_handle = Some(raw_handle);
+        for index in 0..self.panes.len() {
+            let ctx = self.make_context();
+            if let Some(proxy) = self.proxy.clone() {
+                ctx.set_request_repaint_callback(move |info| {
+                    let when = Instant::now() + info.delay;
+                    let _ = proxy.send_event(UserEvent::RequestPaneRepaint { index, when, cumulative_pass_nr: info.current_cumulative_pass_nr });
+                });
+            }
+            self.panes[index].attach(&w, ctx);
+        }
+
+        self.main_window.get_or_insert(w);
+
+        // e.g. Wayland reports 0x0 until the compositor configures the window
+        if (size.width > 0) && (size.height > 0) {
+            self.handle_create_renderer(size).await?;
+        } else {
+            log::info!("Defer creating the renderer until the window has a size");
+        }
+        Ok(())
+    }
+
+    async fn handle_create_renderer(&mut self, size: PhysicalSize<u32>) -> Result<(), anyhow::Error> {
+        let (Some(w), Some(state)) = (self.main_window.as_ref(), self.window_state.as_mut()) else { return Ok(()) };
+
+        let screen = render::ScreenDescriptor {
+            pixel_per_point: self.state.pixels_per_point(w),
+            screen_width: size.width,
+            screen_height: size.height,
+        };
+
+        let mut renderer = render::WgpuRenderer::create_with_config(screen.screen_width, screen.screen_height, Arc::clone(w), &self.renderer_config).await?;
+        renderer.request_resize(&screen);
+        // lets egui (font atlas, `load_texture` callers) know how large a texture may be
+        state.set_max_texture_side(renderer.device().limits().max_texture_dimension_2d as usize);
+
+        // the font atlas is built by the first pass; upload it now so that the first visible frame already has glyphs
+        state.egui_ctx().set_pixels_per_point(screen.pixel_per_point);
+        let input = state.take_egui_input(w);
+        let output = state.egui_ctx().run(input, |_| {});
+        renderer.update_textures(&output.textures_delta);
+
+        for pane in &mut self.panes {
+            pane.create_renderer(w, &renderer, screen.pixel_per_point);
+        }
+
+        self.state.dithering = renderer.dithering();
+        self.renderer = Some(renderer);
+        w.request_redraw();
         Ok(())
     }
 
-    fn handle_close_requested(&self, event_loop: &ActiveEventLoop) {
+    fn handle_close_requested(&mut self, event_loop: &ActiveEventLoop) {
         log::info!("Terminating App...");
+        self.save_settings();
         event_loop.exit();
     }
 
-    fn handle_resize(&mut self, _event_loop: &ActiveEventLoop, size: PhysicalSize<u32>) {
+    fn save_settings(&mut self) {
+        let (Some(path), Some(w)) = (self.settings_path.as_ref(), self.main_window.as_ref()) else { return };
+
+        // keep the windowed geometry while fullscreen or minimized
+        let size = w.inner_size();
+        if !self.state.fullscreen && (size.width > 0) && (size.height > 0) {
+            self.settings.width = size.width;
+            self.settings.height = size.height;
+            self.settings.position = w.outer_position().ok().map(|pos| (pos.x, pos.y));
+        }
+        self.settings.zoom_factor = self.state.zoom_factor;
+
+        if let Err(err) = self.settings.save(path) {
+            log::warn!("Unable to save window settings (reason: {err})");
+        }
+    }
+
+    fn handle_toggle_fullscreen(&mut self, _event_loop: &ActiveEventLoop) {
+        let Some(w) = self.main_window.as_ref() else { return };
+
+        self.state.fullscreen = !self.state.fullscreen;
+        log::info!("Fullscreen: {}", self.state.fullscreen);
+        // the following Resized event reconfigures the surface
+        w.set_fullscreen(self.state.fullscreen.then_some(Fullscreen::Borderless(None)));
+        // some window managers drop the level along with the fullscreen state
+        if !self.state.fullscreen {
+            w.set_window_level(self.window_level);
+        }
+    }
+
+    /// Ctrl+`=`/`-`/`0` and Ctrl+wheel zoom the whole UI.
+    /// Returns whether the event was taken, in which case egui must not see it (e.g. to scroll the hovered widget).
+    fn handle_zoom_shortcut(&mut self, event: &WindowEvent) -> bool {
+        const ZOOM_STEP: f32 = 0.1;
+
+        let (Some(w), Some(s)) = (self.main_window.as_ref(), self.window_state.as_ref()) else { return false };
+        if !s.egui_input().modifiers.command { return false };
+
+        let zoom_factor = match event {
+            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(code), state: ElementState::Pressed, .. }, .. } => match code {
+                KeyCode::Equal | KeyCode::NumpadAdd => self.state.zoom_factor + ZOOM_STEP,
+                KeyCode::Minus | KeyCode::NumpadSubtract => self.state.zoom_factor - ZOOM_STEP,
+                KeyCode::Digit0 | KeyCode::Numpad0 => 1.0,
+                _ => return false,
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.state.zoom_factor + scroll_lines(delta, egui_winit::pixels_per_point(s.egui_ctx(), w)) * ZOOM_STEP
+            }
+            _ => return false,
+        };
+        self.state.set_zoom(zoom_factor);
+        true
+    }
+
+    fn handle_resize(&mut self, event_loop: &ActiveEventLoop, size: PhysicalSize<u32>) {
         log::info!("Resize requested: width: {width}, height: {height}", width = size.width, height = size.height);
+        if self.renderer.is_none() && (size.width > 0) && (size.height > 0) {
+            if let Err(err) = pollster::block_on(self.handle_create_renderer(size)) {
+                log::error!("Unable to create the renderer, terminating App... (reason: {err})");
+                event_loop.exit();
+            }
+            return;
+        }
         if let (Some(w), Some(renderer)) = (self.main_window.as_ref(), self.renderer.as_mut()) && (size.width > 0) && (size.height > 0) {
             let screen = render::ScreenDescriptor {
-                pixel_per_point: w.scale_factor() as f32 * self.state.zoom_factor,
+                pixel_per_point: self.state.pixels_per_point(w),
                 screen_width: size.width,
                 screen_height: size.height,
             };
             renderer.request_resize(&screen);
         }
+        self.frame_dirty = true;
+    }
+
+    fn handle_scale_factor_changed(&mut self, _event_loop: &ActiveEventLoop, scale_factor: f64) {
+        log::info!("Scale factor changed: {scale_factor}");
+        if let (Some(w), Some(s), Some(renderer)) = (self.main_window.as_ref(), self.window_state.as_ref(), self.renderer.as_mut()) {
+            let size = w.inner_size();
+            let pixel_per_point = self.state.pixels_per_point(w);
+            s.egui_ctx().set_pixels_per_point(pixel_per_point);
+
+            if (size.width > 0) && (size.height > 0) {
+                let screen = render::ScreenDescriptor {
+                    pixel_per_point,
+                    screen_width: size.width,
+                    screen_height: size.height,
+                };
+                renderer.request_resize(&screen);
+            }
+        }
+        self.frame_dirty = true;
     }
 
-    fn handle_redraw(&mut self, _event_loop: &ActiveEventLoop) {
+    fn handle_redraw(&mut self, event_loop: &ActiveEventLoop) {
+        if let Some(r) = self.renderer.as_mut() && r.is_device_lost() {
+            // child viewports still hold the lost device, sync_viewports recreates them
+            self.viewports.clear();
+            if let Err(err) = pollster::block_on(r.recover_device()) {
+                log::error!("Unable to recover the GPU device, terminating App... (reason: {err})");
+                event_loop.exit();
+                return;
+            }
+            for pane in &mut self.panes {
+                pane.recover_renderer(r);
+            }
+            self.frame_dirty = true;
+        }
+
+        let frame_start = Instant::now();
+        let min_frame_time = self.max_fps.map(|fps| Duration::from_secs(1) / fps);
+        if let (Some(min_frame_time), Some(last_frame)) = (min_frame_time, self.last_frame) && (frame_start < last_frame + min_frame_time) {
+            // too early for the cap, redraw at the next slot instead
+            let next_slot = last_frame + min_frame_time;
+            self.next_repaint = Some(self.next_repaint.map_or(next_slot, |t| t.min(next_slot)));
+            return;
+        }
+
         if let (Some(w), Some(s), Some(r)) = (self.main_window.as_ref(), self.window_state.as_mut(), self.renderer.as_mut()) {
             if let Some(y) = w.is_minimized() && y {
                 log::info!("Skip to render because the window is minimized");
                 return;
             }
-            let (scale_changed, output) = self.state.update(w, s);
-            // dump_output(&output).expect("failed to dump egui output");
+            let size = w.inner_size();
+            if (size.width == 0) || (size.height == 0) {
+                log::info!("Skip to render until the window has a size");
+                return;
+            }
+            if self.occluded {
+                log::info!("Skip to render because the window is occluded");
+                return;
+            }
+            self.last_frame = Some(frame_start);
+            let count = 1 + self.panes.len();
+            let (_, main_width) = pane::column(0, count, size.width);
+            let (scale_changed, mut output) = self.state.update(w, s, main_width);
+            if std::mem::take(&mut self.dump_next_frame) && let Err(err) = dump_output(&output) {
+                log::warn!("Unable to dump egui output (reason: {err})");
+            }
 
-            let triangles = s.egui_ctx().tessellate(output.shapes, output.pixels_per_point);
+            // clipboard, cursor, IME and links requested by egui
+            s.handle_platform_output(w, output.platform_output);
 
-            w.request_redraw(); // Reserve the next redrawing
+            let pane_frames = self.panes.iter_mut().enumerate()
+                .map(|(i, pane)| {
+                    let (origin_x, width) = pane::column(i + 1, count, size.width);
+                    pane.update(w, output.pixels_per_point, origin_x, width)
+                })
+                .collect::<Vec<_>>()
+            ;
+
+            let repaint_delay = match self.state.run_mode {
+                RunMode::Continuous => Duration::ZERO,
+                RunMode::Reactive => pane_frames.iter().flatten()
+                    .map(|frame| frame.repaint_delay)
+                    .fold(output.viewport_output.get(&egui::ViewportId::ROOT).map_or(Duration::MAX, |vp| vp.repaint_delay), Duration::min),
+            };
+            // the slot is measured from the frame start, so the time spent in render() counts towards it
+            let repaint_delay = match min_frame_time {
+                Some(min_frame_time) => repaint_delay.max(min_frame_time.saturating_sub(frame_start.elapsed())),
+                None => repaint_delay,
+            };
+            self.next_repaint = match self.pause_when_unfocused && !self.focused {
+                // kept pending until the window is focused again
+                true => Instant::now().checked_add(repaint_delay),
+                false => {
+                    self.egui_redraw_requested |= repaint_delay.is_zero();
+                    schedule_repaint(w, repaint_delay)
+                }
+            };
+
+            if std::mem::take(&mut self.state.screenshot_requested) {
+                r.request_screenshot(save_screenshot);
+            }
+
+            let dithering_changed = r.dithering() != self.state.dithering;
+            r.set_dithering(self.state.dithering);
+
+            // the presented frame is still up to date, skip the GPU work (only tracked for the main UI, panes always redraw)
+            let dirty = std::mem::take(&mut self.frame_dirty) || scale_changed || dithering_changed;
+            if self.state.is_unchanged(&output.textures_delta, &output.shapes) && !dirty && self.panes.is_empty() {
+                self.sync_viewports(event_loop, std::mem::take(&mut output.viewport_output));
+                return;
+            }
+
+            let triangles = s.egui_ctx().tessellate(output.shapes, output.pixels_per_point);
+            for (id, shapes) in self.state.take_stale_regions() {
+                r.retain_meshes(id, s.egui_ctx().tessellate(shapes, output.pixels_per_point));
+            }
+            // keep the background in line with the theme
+            r.set_clear_color(s.egui_ctx().style().visuals.window_fill);
 
-            let size = w.inner_size();
             let screen = render::ScreenDescriptor {
                 pixel_per_point: output.pixels_per_point,
                 screen_width: size.width,
                 screen_height: size.height,
             };
 
-            if scale_changed {
+            // the surface may still have the size it was created with if no Resized has arrived yet
+            if r.surface_size() != (size.width, size.height) {
+                r.request_resize(&screen);
+            }
+            else if scale_changed {
                 r.request_rescale(&screen);
             }
 
-            match r.render(&screen, &triangles, &output.textures_delta) {
-                Ok(_) => {},
-                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+            let mut panes = self.panes.iter_mut().zip(&pane_frames)
+                .filter_map(|(pane, frame)| pane.render_pane(frame.as_ref()?))
+                .collect::<Vec<_>>()
+            ;
+            match r.render_with_panes(&screen, &triangles, &output.textures_delta, &mut panes) {
+                Ok(stats) => self.state.frame_stats = stats,
+                // transient while the window is being resized (e.g. dragging its border on Windows); drawn again at the new size
+                Err(err @ (wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated)) => {
+                    log::debug!("Skip a frame, the surface is reconfigured (reason: {err})");
                     r.request_resize(&screen);
+                    self.frame_dirty = true;
+                    w.request_redraw();
+                }
+                // the next redraw tries again, even if nothing else asks for one
+                Err(wgpu::SurfaceError::Timeout) => {
+                    log::debug!("Skip a frame, the surface texture timed out");
+                    self.frame_dirty = true;
+                    w.request_redraw();
+                }
+                Err(wgpu::SurfaceError::OutOfMemory) => {
+                    log::error!("Unable to render, terminating App... (reason: out of memory)");
+                    event_loop.exit();
+                }
+                Err(e) => {
+                    log::error!("Unable to render (reason: {e})");
+                    self.frame_dirty = true;
                 }
-                Err(e) => log::error!("Unable to render (reason: {e}"),
             }
+
+            self.sync_viewports(event_loop, std::mem::take(&mut output.viewport_output));
         }
         // println!("redraw requested");
     }
+
+    /// Applies the commands sent to each viewport, creates OS windows for newly shown deferred viewports
+    /// and drops the ones egui no longer shows (or that were asked to close)
+    fn sync_viewports(&mut self, event_loop: &ActiveEventLoop, viewport_output: egui::OrderedViewportIdMap<egui::ViewportOutput>) {
+        if let Some(vp) = viewport_output.get(&egui::ViewportId::ROOT)
+            && let Some(level) = vp.commands.iter().rev().find_map(|command| match command {
+                egui::ViewportCommand::WindowLevel(level) => Some(*level),
+                _ => None,
+            })
+        {
+            self.window_level = to_window_level(level);
+        }
+        // e.g. a "Quit" menu item, which closes the app like the window's close button
+        if let (Some(w), Some(s)) = (self.main_window.as_ref(), self.window_state.as_ref())
+            && let Some(vp) = viewport_output.get(&egui::ViewportId::ROOT)
+            && apply_viewport_commands(w, &vp.commands, egui_winit::pixels_per_point(s.egui_ctx(), w))
+        {
+            self.handle_close_requested(event_loop);
+            return;
+        }
+
+        let (Some(s), Some(r)) = (self.window_state.as_ref(), self.renderer.as_ref()) else { return };
+
+        self.viewports.retain(|id, _| viewport_output.contains_key(id));
+
+        for (id, output) in viewport_output {
+            // the root is the main window and immediate viewports are embedded
+            let Some(ui_cb) = output.viewport_ui_cb else { continue };
+
+            let vp = match self.viewports.entry(id) {
+                hash_map::Entry::Occupied(entry) => {
+                    let vp = entry.into_mut();
+                    vp.set_ui_cb(ui_cb);
+                    vp
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    match viewport::ChildViewport::create(event_loop, s.egui_ctx(), r, id, &output.builder, ui_cb) {
+                        Ok(vp) => entry.insert(vp),
+                        Err(err) => {
+                            log::error!("Unable to create a viewport window (reason: {err})");
+                            continue;
+                        }
+                    }
+                }
+            };
+            // shown again on the next pass unless the UI stops showing it
+            if vp.apply_commands(&output.commands) {
+                self.viewports.remove(&id);
+            }
+        }
+    }
 }
 
-impl ApplicationHandler for App {
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ApplicationHandler<UserEvent> for App {
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        // a pending repaint of an occluded (or paused unfocused) main window waits until it shows again
+        if let (Some(w), Some(next_repaint)) = (self.main_window.as_ref(), self.next_repaint) && !self.is_repaint_paused() && (next_repaint <= now) {
+            self.next_repaint = None;
+            self.egui_redraw_requested = true;
+            w.request_redraw();
+        }
+        for vp in self.viewports.values_mut() {
+            vp.wake_if_due(now);
+        }
+
+        // screenshots are delivered once their copy has finished, which no event announces
+        let screenshot_pending = self.renderer.as_mut().is_some_and(|r| r.poll_screenshots());
+        let screenshot_poll = screenshot_pending.then(|| now + Self::SCREENSHOT_POLL_INTERVAL);
+
+        let next_repaint = self.viewports.values().filter_map(|vp| vp.next_repaint())
+            .chain(self.next_repaint.filter(|_| !self.is_repaint_paused()))
+            .chain(screenshot_poll)
+            .min()
+        ;
+        event_loop.set_control_flow(match next_repaint {
+            Some(t) => ControlFlow::WaitUntil(t),
+            None => ControlFlow::Wait,
+        });
+    }
+
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         pollster::block_on(self.handle_prepare_window_frame(event_loop))
             .map_err(|err| anyhow::anyhow!("faild to create a main window (reason: {err}"))
@@ -161,34 +1147,117 @@ impl ApplicationHandler for App {
         ;
     }
 
+    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: UserEvent) {
+        match event {
+            UserEvent::RequestRepaint { viewport_id, when, cumulative_pass_nr } => {
+                let Some(s) = self.window_state.as_ref() else { return };
+                // a pass has run since the request
+                if s.egui_ctx().cumulative_pass_nr_for(viewport_id) != cumulative_pass_nr { return };
+
+                if viewport_id == egui::ViewportId::ROOT {
+                    self.next_repaint = Some(self.next_repaint.map_or(when, |t| t.min(when)));
+                }
+                else if let Some(vp) = self.viewports.get_mut(&viewport_id) {
+                    vp.request_repaint_at(when);
+                }
+                // about_to_wait wakes the due windows
+            }
+            UserEvent::RequestPaneRepaint { index, when, cumulative_pass_nr } => {
+                let Some(s) = self.panes.get(index).and_then(pane::SplitPane::state) else { return };
+                if s.egui_ctx().cumulative_pass_nr_for(egui::ViewportId::ROOT) != cumulative_pass_nr { return };
+
+                self.next_repaint = Some(self.next_repaint.map_or(when, |t| t.min(when)));
+            }
+        }
+    }
+
     fn window_event(
         &mut self,
         event_loop: &winit::event_loop::ActiveEventLoop,
-        _window_id: winit::window::WindowId,
+        window_id: winit::window::WindowId,
         event: winit::event::WindowEvent)
     {
-        let (Some(w), Some(state)) = (self.main_window.as_ref(), self.window_state.as_mut()) else { return };
-        let _ = state.on_window_event(&w, &event);
+        if let Some((&id, vp)) = self.viewports.iter_mut().find(|(_, vp)| vp.window().id() == window_id) {
+            vp.handle_window_event(event);
+            if vp.is_closed() {
+                self.viewports.remove(&id);
+            }
+            return;
+        }
+
+        if self.handle_zoom_shortcut(&event) {
+            if let Some(w) = self.main_window.as_ref() {
+                w.request_redraw();
+            }
+            return;
+        }
+
+        let Some(w) = self.main_window.clone() else { return };
+        // every key reaches egui here (Tab/Shift+Tab focus traversal, arrows, Enter, Space), only the zoom shortcuts above are taken.
+        // Ime events are forwarded here too; IME is enabled by handle_platform_output while a text edit has focus.
+        // egui-winit only reports text input as consumed, but Escape also clears the focus of a widget reached with Tab
+        let Some((response, has_focus)) = self.forward_window_event(&w, &event) else { return };
+        // egui-winit also asks to repaint on RedrawRequested itself, which is being handled below
+        if response.repaint && !matches!(event, WindowEvent::RedrawRequested) {
+            self.egui_redraw_requested = true;
+            w.request_redraw();
+        }
 
         match event {
-            WindowEvent::CloseRequested => {
-                if self.main_window.is_some() {
-                    self.handle_close_requested(event_loop);
-                }
+            WindowEvent::CloseRequested if self.main_window.is_some() => {
+                self.handle_close_requested(event_loop);
             }
-            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(code), state: key_state, .. }, .. } => {
-                match (code, key_state) {
-                    (KeyCode::Escape, ElementState::Pressed) => {
-                        self.handle_close_requested(event_loop);
-                    }
-                    _ => {}
-                }
+            // a focused widget (or an IME composition) takes Escape for itself
+            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(KeyCode::Escape), state: ElementState::Pressed, .. }, .. } if !response.consumed && !has_focus => {
+                self.handle_close_requested(event_loop);
+            }
+            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(KeyCode::F11), state: ElementState::Pressed, repeat: false, .. }, .. } => {
+                self.handle_toggle_fullscreen(event_loop);
+            }
+            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(KeyCode::F12), state: ElementState::Pressed, repeat: false, .. }, .. } => {
+                self.dump_next_frame = true;
+                w.request_redraw();
+            }
+            WindowEvent::KeyboardInput { event: event::KeyEvent{ physical_key: PhysicalKey::Code(KeyCode::F3), state: ElementState::Pressed, repeat: false, .. }, .. } => {
+                self.state.show_fps = !self.state.show_fps;
+                w.request_redraw();
             }
             WindowEvent::Resized(size) => {
                 self.handle_resize(event_loop, size);
             }
+            // the only way winit reports a window moved onto a monitor with another scale (`Window::scale_factor` changes with it).
+            // Panes take the main UI's pixels per point every frame, child viewports handle the event themselves (see `ChildViewport`)
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                self.handle_scale_factor_changed(event_loop, scale_factor);
+            }
+            WindowEvent::Occluded(occluded) => {
+                log::info!("Occluded: {occluded}");
+                self.occluded = occluded;
+                if !occluded {
+                    self.frame_dirty = true;
+                    w.request_redraw();
+                }
+            }
+            // egui-winit has already passed it on (`RawInput::focused`, `Event::WindowFocused`) and asked for a redraw
+            WindowEvent::Focused(focused) => {
+                self.focused = focused;
+            }
+            WindowEvent::DroppedFile(_) => {
+                // egui-winit only records the path
+                if let Some(file) = self.column_state_mut(self.hovered_column).and_then(|s| s.egui_input_mut().dropped_files.last_mut()) {
+                    load_dropped_file(file);
+                }
+            }
             WindowEvent::RedrawRequested => {
+                if !std::mem::take(&mut self.egui_redraw_requested) {
+                    self.frame_dirty = true;
+                }
                 self.handle_redraw(event_loop);
+                // the frame asking for it is on screen before the next one runs the UI
+                if self.take_redraw_now_request() {
+                    self.wait_for_gpu();
+                    self.redraw_now(event_loop);
+                }
             }
             _ => {
 
@@ -197,7 +1266,130 @@ impl ApplicationHandler for App {
     }
 }
 
-#[allow(unused)]
+/// Vertical wheel movement in lines (positive = away from the user), the unit Ctrl+wheel zooms by
+fn scroll_lines(delta: &MouseScrollDelta, pixels_per_point: f32) -> f32 {
+    // egui's scroll unit
+    const POINTS_PER_LINE: f32 = 50.0;
+
+    match delta {
+        MouseScrollDelta::LineDelta(_, y) => *y,
+        // physical pixels (e.g. trackpads), converted to points like egui-winit does for scrolling
+        MouseScrollDelta::PixelDelta(pos) => pos.y as f32 / pixels_per_point / POINTS_PER_LINE,
+    }
+}
+
+/// Redraws immediately for a zero delay, otherwise returns when to redraw (`None` = on input only)
+fn schedule_repaint(window: &Window, repaint_delay: Duration) -> Option<Instant> {
+    if repaint_delay.is_zero() {
+        window.request_redraw();
+        None
+    } else {
+        Instant::now().checked_add(repaint_delay)
+    }
+}
+
+/// Lets a custom title bar (e.g. an area sensing `click_and_drag`) move the window when dragged
+/// and maximize or restore it when double-clicked, like the OS title bar of a decorated window
+pub fn title_bar_interaction(ui: &egui::Ui, response: &egui::Response) {
+    if response.double_clicked() {
+        let maximized = ui.input(|i| i.viewport().maximized.unwrap_or(false));
+        ui.ctx().send_viewport_cmd(egui::ViewportCommand::Maximized(!maximized));
+    }
+    else if response.drag_started_by(egui::PointerButton::Primary) {
+        ui.ctx().send_viewport_cmd(egui::ViewportCommand::StartDrag);
+    }
+}
+
+/// Asks from the main window's UI for [`App::redraw_now`] right after the current frame,
+/// e.g. so that a "Saving..." state drawn by this frame is on screen before the next one blocks
+pub fn request_redraw_now(ctx: &egui::Context) {
+    ctx.data_mut(|d| d.insert_temp(redraw_now_id(), true));
+    // honored after a frame drawn for `RedrawRequested`, which a frame drawn by `redraw_now` itself is not
+    ctx.request_repaint();
+}
+
+fn redraw_now_id() -> egui::Id {
+    egui::Id::new("egui_demo::redraw_now")
+}
+
+/// Applies the window commands egui sent to a viewport; the others are left to egui-winit (e.g. IME) or ignored.
+/// Returns whether the viewport was asked to close.
+fn apply_viewport_commands(window: &Window, commands: &[egui::ViewportCommand], pixels_per_point: f32) -> bool {
+    let mut close = false;
+    for command in commands {
+        match command {
+            egui::ViewportCommand::Close => close = true,
+            egui::ViewportCommand::Title(title) => window.set_title(title),
+            egui::ViewportCommand::InnerSize(size) => {
+                // a size the platform applies right away still comes as Resized
+                let _ = window.request_inner_size(PhysicalSize::new(size.x.max(1.0) * pixels_per_point, size.y.max(1.0) * pixels_per_point));
+            }
+            egui::ViewportCommand::Maximized(maximized) => window.set_maximized(*maximized),
+            egui::ViewportCommand::WindowLevel(level) => window.set_window_level(to_window_level(*level)),
+            egui::ViewportCommand::StartDrag => {
+                // X11 would keep the pointer grabbed if the window is not focused
+                if window.has_focus() && let Err(err) = window.drag_window() {
+                    log::warn!("Unable to drag the window (reason: {err})");
+                }
+            }
+            // pointer warps (e.g. for dragging past the screen edge)
+            egui::ViewportCommand::CursorPosition(pos) => warp_cursor(window, *pos, pixels_per_point),
+            _ => log::trace!("Ignored viewport command {command:?}"),
+        }
+    }
+    close
+}
+
+fn to_window_level(level: egui::viewport::WindowLevel) -> WindowLevel {
+    match level {
+        egui::viewport::WindowLevel::Normal => WindowLevel::Normal,
+        egui::viewport::WindowLevel::AlwaysOnTop => WindowLevel::AlwaysOnTop,
+        egui::viewport::WindowLevel::AlwaysOnBottom => WindowLevel::AlwaysOnBottom,
+    }
+}
+
+/// Moves the pointer to `pos` (in points), kept inside the window.
+/// Platforms that do not allow it (e.g. Wayland) are skipped quietly.
+fn warp_cursor(window: &Window, pos: egui::Pos2, pixels_per_point: f32) {
+    let size = window.inner_size();
+    let x = (pos.x * pixels_per_point).clamp(0.0, size.width.saturating_sub(1) as f32);
+    let y = (pos.y * pixels_per_point).clamp(0.0, size.height.saturating_sub(1) as f32);
+    match window.set_cursor_position(PhysicalPosition::new(x, y)) {
+        Ok(()) => {}
+        Err(winit::error::ExternalError::NotSupported(_)) => log::debug!("Cursor warping is not supported on this platform"),
+        Err(err) => log::warn!("Unable to warp the cursor (reason: {err})"),
+    }
+}
+
+/// Writes a frame captured by `request_screenshot` to `screenshot-<unix time>.png` in the working directory
+fn save_screenshot(image: egui::ColorImage) {
+    let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let path = PathBuf::from(format!("screenshot-{secs}.png"));
+    let [width, height] = image.size;
+    match image::save_buffer(&path, bytemuck::cast_slice(&image.pixels), width as u32, height as u32, image::ColorType::Rgba8) {
+        Ok(()) => log::info!("Screenshot saved to {}", path.display()),
+        Err(err) => log::error!("Unable to save the screenshot to {} (reason: {err})", path.display()),
+    }
+}
+
+/// Fills in the name, modification time and (for small files) the contents of a dropped file
+fn load_dropped_file(file: &mut egui::DroppedFile) {
+    const MAX_DROPPED_FILE_BYTES: u64 = 16 * 1024 * 1024;
+
+    let Some(path) = file.path.as_ref() else { return };
+    file.name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let Ok(metadata) = std::fs::metadata(path) else { return };
+    file.last_modified = metadata.modified().ok();
+
+    if metadata.is_file() && (metadata.len() <= MAX_DROPPED_FILE_BYTES) {
+        match std::fs::read(path) {
+            Ok(bytes) => file.bytes = Some(bytes.into()),
+            Err(err) => log::warn!("Unable to read dropped file {} (reason: {err})", path.display()),
+        }
+    }
+}
+
 fn dump_output(output: &egui::FullOutput) -> Result<(), anyhow::Error> {
     println!("** Dump/ppp: {}", output.pixels_per_point);
     println!("** Dump output for platform");
@@ -223,3 +1415,29 @@ fn dump_output(output: &egui::FullOutput) -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scroll_lines_from_line_delta() {
+        // lines do not depend on the pixel density
+        for ppp in [1.0, 2.0] {
+            assert_eq!(scroll_lines(&MouseScrollDelta::LineDelta(0.0, 1.0), ppp), 1.0);
+            assert_eq!(scroll_lines(&MouseScrollDelta::LineDelta(0.0, -3.0), ppp), -3.0);
+            // horizontal movement does not zoom
+            assert_eq!(scroll_lines(&MouseScrollDelta::LineDelta(2.0, 0.0), ppp), 0.0);
+        }
+    }
+
+    #[test]
+    fn scroll_lines_from_pixel_delta() {
+        // 50 points make a line
+        assert_eq!(scroll_lines(&MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, 50.0)), 1.0), 1.0);
+        assert_eq!(scroll_lines(&MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, -25.0)), 1.0), -0.5);
+        // twice the pixels for the same distance on a HiDPI screen
+        assert_eq!(scroll_lines(&MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, 100.0)), 2.0), 1.0);
+        assert_eq!(scroll_lines(&MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, -50.0)), 2.0), -0.5);
+    }
+}
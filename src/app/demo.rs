@@ -0,0 +1,109 @@
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+/// UI shown when no UI is given through `App::with_ui`
+pub fn demo_ui() -> impl FnMut(&egui::Context) {
+    let mut text = String::new();
+    let show_child_viewport = Arc::new(AtomicBool::new(false));
+    let mut dropped_files = Vec::<egui::DroppedFile>::new();
+    let mut dropped_images = Vec::<Result<egui::TextureHandle, String>>::new();
+
+    move |cx| {
+        let dropped = cx.input(|i| {
+            if !i.raw.dropped_files.is_empty() {
+                dropped_files.clone_from(&i.raw.dropped_files);
+            }
+            !i.raw.dropped_files.is_empty()
+        });
+        if dropped {
+            dropped_images = dropped_files.iter().filter_map(|file| load_image(cx, file)).collect();
+        }
+
+        egui::Area::new(egui::Id::new("winit + egui + wgpu says hello!"))
+            .show(cx, |ui| {
+                ui.label("Label!");
+                if ui.button("boom!").clicked() {
+                    println!("Boom!");
+                }
+
+                ui.text_edit_singleline(&mut text);
+                ui.hyperlink_to("egui", "https://github.com/emilk/egui");
+                gradient(ui);
+                egui::widgets::global_theme_preference_buttons(ui);
+
+                let mut show = show_child_viewport.load(Ordering::Relaxed);
+                if ui.checkbox(&mut show, "Show child viewport").changed() {
+                    show_child_viewport.store(show, Ordering::Relaxed);
+                }
+
+                let hovered = cx.input(|i| i.raw.hovered_files.len());
+                ui.label(match hovered {
+                    0 => "Drop files here".to_owned(),
+                    n => format!("Dropping {n} file(s)..."),
+                });
+                for file in &dropped_files {
+                    let size = file.bytes.as_ref().map_or("not loaded".to_owned(), |bytes| format!("{} bytes", bytes.len()));
+                    ui.label(format!("{} ({size})", file.name));
+                }
+                for image in &dropped_images {
+                    match image {
+                        Ok(texture) => {
+                            ui.add(egui::Image::new(texture).max_size(egui::vec2(320.0, 240.0)));
+                        }
+                        Err(err) => {
+                            ui.colored_label(ui.visuals().error_fg_color, err);
+                        }
+                    }
+                }
+            })
+        ;
+
+        if show_child_viewport.load(Ordering::Relaxed) {
+            let show_child_viewport = Arc::clone(&show_child_viewport);
+            cx.show_viewport_deferred(
+                egui::ViewportId::from_hash_of("child viewport"),
+                egui::ViewportBuilder::default().with_title("Child viewport").with_inner_size([320.0, 240.0]),
+                move |cx, _class| {
+                    egui::CentralPanel::default().show(cx, |ui| {
+                        ui.label("Hello from a child viewport!");
+                    });
+                    if cx.input(|i| i.viewport().close_requested()) {
+                        show_child_viewport.store(false, Ordering::Relaxed);
+                    }
+                }
+            );
+        }
+    }
+}
+
+/// Dark, shallow gradient that bands visibly on 8-bit displays unless dithered
+fn gradient(ui: &mut egui::Ui) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(320.0, 40.0), egui::Sense::hover());
+    let left = egui::Color32::from_rgb(20, 24, 32);
+    let right = egui::Color32::from_rgb(40, 48, 64);
+
+    let mut mesh = egui::Mesh::default();
+    mesh.colored_vertex(rect.left_top(), left);
+    mesh.colored_vertex(rect.right_top(), right);
+    mesh.colored_vertex(rect.left_bottom(), left);
+    mesh.colored_vertex(rect.right_bottom(), right);
+    mesh.add_triangle(0, 1, 2);
+    mesh.add_triangle(1, 3, 2);
+    ui.painter().add(mesh);
+}
+
+/// Decodes a dropped file into a texture (`None` when its contents were not loaded, e.g. too large)
+fn load_image(cx: &egui::Context, file: &egui::DroppedFile) -> Option<Result<egui::TextureHandle, String>> {
+    let bytes = file.bytes.as_ref()?;
+    let max_side = cx.input(|i| i.max_texture_side) as u32;
+    let image = image::load_from_memory(bytes)
+        .map_err(|err| format!("{}: not a supported image ({err})", file.name))
+        .map(|image| match (image.width() > max_side) || (image.height() > max_side) {
+            true => image.thumbnail(max_side, max_side).into_rgba8(),
+            false => image.into_rgba8(),
+        })
+    ;
+    Some(image.map(|image| {
+        let size = [image.width() as usize, image.height() as usize];
+        cx.load_texture(&file.name, egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw()), egui::TextureOptions::LINEAR)
+    }))
+}
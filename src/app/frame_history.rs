@@ -0,0 +1,55 @@
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use crate::render;
+
+/// Time between the last frames, averaged for the FPS overlay
+pub struct FrameHistory {
+    last_frame: Option<Instant>,
+    intervals: VecDeque<Duration>,
+    total: Duration,
+}
+impl FrameHistory {
+    /// Frames the average is taken over
+    const LEN: usize = 60;
+
+    pub fn new() -> Self {
+        Self { last_frame: None, intervals: VecDeque::with_capacity(Self::LEN + 1), total: Duration::ZERO }
+    }
+
+    pub fn on_frame(&mut self, now: Instant) {
+        let Some(last_frame) = self.last_frame.replace(now) else { return };
+
+        let interval = now.saturating_duration_since(last_frame);
+        self.intervals.push_back(interval);
+        self.total += interval;
+        if self.intervals.len() > Self::LEN && let Some(oldest) = self.intervals.pop_front() {
+            self.total -= oldest;
+        }
+    }
+
+    /// `None` until two frames have been drawn
+    pub fn mean_frame_time(&self) -> Option<Duration> {
+        (!self.intervals.is_empty()).then(|| self.total / self.intervals.len() as u32)
+    }
+
+    /// FPS, frame time and the stats of the previous frame in the top left corner.
+    /// In `RunMode::Reactive`, idle time between redraws counts towards the frame time.
+    pub fn show(&self, cx: &egui::Context, stats: &render::FrameStats) {
+        egui::Area::new(egui::Id::new("fps overlay"))
+            .anchor(egui::Align2::LEFT_TOP, [8.0, 8.0])
+            .interactable(false)
+            .order(egui::Order::Foreground)
+            .show(cx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let frame_time = self.mean_frame_time().unwrap_or_default().as_secs_f64();
+                    let fps = if frame_time > 0.0 { 1.0 / frame_time } else { 0.0 };
+                    ui.monospace(format!("FPS: {fps:.1} ({:.2} ms)", frame_time * 1000.0));
+                    ui.monospace(format!("draw calls: {}, vertices: {}", stats.draw_calls, stats.vertices));
+                    if let Some(gpu_time_ms) = stats.gpu_time_ms {
+                        ui.monospace(format!("GPU time: {gpu_time_ms:.3} ms"));
+                    }
+                });
+            })
+        ;
+    }
+}
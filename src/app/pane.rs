@@ -0,0 +1,154 @@
+use std::time::Duration;
+use winit::{dpi::PhysicalPosition, event::{self, WindowEvent}, window::Window};
+
+use crate::render;
+use super::UiFn;
+
+/// Another egui context shown in its own column of the main window (e.g. a preview next to an editor).
+/// It keeps its own memory, textures and input; the main UI takes the first column.
+pub struct SplitPane {
+    ui: UiFn,
+    /// created along with the main window
+    state: Option<egui_winit::State>,
+    renderer: Option<render::WgpuRenderer>,
+}
+
+/// Output of one pass of a pane, rendered along with the main UI
+pub struct PaneFrame {
+    origin_x: u32,
+    screen: render::ScreenDescriptor,
+    triangles: Vec<egui::ClippedPrimitive>,
+    textures_delta: egui::TexturesDelta,
+    pub repaint_delay: Duration,
+}
+
+impl SplitPane {
+    pub fn new(ui: UiFn) -> Self {
+        Self { ui, state: None, renderer: None }
+    }
+
+    pub fn attach(&mut self, window: &Window, ctx: egui::Context) {
+        self.state = Some(egui_winit::State::new(
+            ctx,
+            egui::viewport::ViewportId::ROOT,
+            window,
+            Some(window.scale_factor() as f32),
+            None,
+            None
+        ));
+    }
+
+    pub fn state(&self) -> Option<&egui_winit::State> {
+        self.state.as_ref()
+    }
+
+    pub fn state_mut(&mut self) -> Option<&mut egui_winit::State> {
+        self.state.as_mut()
+    }
+
+    /// Shares the device of the main window's renderer and uploads the font atlas like the main context does
+    pub fn create_renderer(&mut self, window: &Window, parent: &render::WgpuRenderer, pixels_per_point: f32) {
+        let Some(state) = self.state.as_mut() else { return };
+
+        let mut renderer = parent.create_pane();
+        state.set_max_texture_side(renderer.device().limits().max_texture_dimension_2d as usize);
+
+        state.egui_ctx().set_pixels_per_point(pixels_per_point);
+        let input = state.take_egui_input(window);
+        let output = state.egui_ctx().run(input, |_| {});
+        renderer.update_textures(&output.textures_delta);
+
+        self.renderer = Some(renderer);
+    }
+
+    /// Follows the main window's renderer onto its recovered device
+    pub fn recover_renderer(&mut self, parent: &render::WgpuRenderer) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            renderer.recover_pane(parent);
+        }
+    }
+
+    /// Runs the UI in the column starting at `origin_x` (physical pixels)
+    pub fn update(&mut self, window: &Window, pixels_per_point: f32, origin_x: u32, width: u32) -> Option<PaneFrame> {
+        let state = self.state.as_mut()?;
+
+        let mut input = state.take_egui_input(window);
+        narrow_input(&mut input, window, width);
+        let ui = &mut self.ui;
+        let output = state.egui_ctx().run(input, |cx| ui(cx));
+        state.egui_ctx().set_pixels_per_point(pixels_per_point);
+
+        let mut platform_output = output.platform_output;
+        // egui places the IME candidate window relative to the pane, the OS relative to the window
+        if let Some(ime) = platform_output.ime.as_mut() {
+            let offset = egui::vec2(origin_x as f32 / egui_winit::pixels_per_point(state.egui_ctx(), window), 0.0);
+            ime.rect = ime.rect.translate(offset);
+            ime.cursor_rect = ime.cursor_rect.translate(offset);
+        }
+        state.handle_platform_output(window, platform_output);
+        let repaint_delay = output.viewport_output.get(&egui::ViewportId::ROOT).map_or(Duration::MAX, |vp| vp.repaint_delay);
+        let triangles = state.egui_ctx().tessellate(output.shapes, pixels_per_point);
+
+        Some(PaneFrame {
+            origin_x,
+            screen: render::ScreenDescriptor {
+                pixel_per_point: pixels_per_point,
+                screen_width: width,
+                screen_height: window.inner_size().height,
+            },
+            triangles,
+            textures_delta: output.textures_delta,
+            repaint_delay,
+        })
+    }
+
+    pub fn render_pane<'a>(&'a mut self, frame: &'a PaneFrame) -> Option<render::Pane<'a>> {
+        Some(render::Pane {
+            renderer: self.renderer.as_mut()?,
+            origin: [frame.origin_x, 0],
+            screen: frame.screen,
+            triangles: &frame.triangles,
+            images: &frame.textures_delta,
+        })
+    }
+}
+
+/// Left edge and width (physical pixels) of column `index` out of `count` equal columns
+pub fn column(index: usize, count: usize, window_width: u32) -> (u32, u32) {
+    let column_width = window_width / count as u32;
+    let origin_x = column_width * index as u32;
+    // the last column takes the remainder
+    match index + 1 == count {
+        true => (origin_x, window_width - origin_x),
+        false => (origin_x, column_width),
+    }
+}
+
+/// Column under `x` (physical pixels)
+pub fn column_at(x: f64, count: usize, window_width: u32) -> usize {
+    let column_width = (window_width / count as u32).max(1);
+    ((x.max(0.0) as u32 / column_width) as usize).min(count - 1)
+}
+
+/// Moves pointer positions into the coordinates of the column starting at `origin_x`
+pub fn translate_event(event: &WindowEvent, origin_x: u32) -> WindowEvent {
+    match event {
+        WindowEvent::CursorMoved { device_id, position } => WindowEvent::CursorMoved {
+            device_id: *device_id,
+            position: PhysicalPosition::new(position.x - origin_x as f64, position.y),
+        },
+        WindowEvent::Touch(touch) => WindowEvent::Touch(event::Touch {
+            location: PhysicalPosition::new(touch.location.x - origin_x as f64, touch.location.y),
+            ..*touch
+        }),
+        _ => event.clone(),
+    }
+}
+
+/// egui-winit sizes the screen after the whole window; narrow it down to a column `width` physical pixels wide
+pub fn narrow_input(input: &mut egui::RawInput, window: &Window, width: u32) {
+    let window_width = window.inner_size().width;
+    if let Some(rect) = input.screen_rect.as_mut() && (window_width > 0) {
+        rect.max.x = rect.min.x + rect.width() * width as f32 / window_width as f32;
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::Path;
+
+/// Window geometry and zoom restored across runs
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct WindowSettings {
+    pub width: u32,
+    pub height: u32,
+    pub position: Option<(i32, i32)>,
+    pub zoom_factor: f32,
+}
+impl WindowSettings {
+    /// Returns `None` if the file is missing or malformed
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        serde_json::from_str::<Self>(&content)
+            .map_err(|err| log::warn!("Ignore malformed window settings (reason: {err})"))
+            .ok()
+            .filter(|settings| (settings.width > 0) && (settings.height > 0))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
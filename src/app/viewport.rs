@@ -0,0 +1,160 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+use winit::{event::WindowEvent, event_loop::ActiveEventLoop, window::Window};
+
+use crate::render;
+
+/// egui deferred viewport shown in its own OS window
+pub struct ChildViewport {
+    window: Arc<Window>,
+    renderer: render::WgpuRenderer,
+    state: egui_winit::State,
+    ui_cb: Arc<egui::DeferredViewportUiCallback>,
+    next_repaint: Option<Instant>,
+    /// asked to close by `ViewportCommand::Close`, the window is dropped by the app
+    closed: bool,
+}
+impl ChildViewport {
+    pub fn create(
+        event_loop: &ActiveEventLoop,
+        ctx: &egui::Context,
+        parent_renderer: &render::WgpuRenderer,
+        id: egui::ViewportId,
+        builder: &egui::ViewportBuilder,
+        ui_cb: Arc<egui::DeferredViewportUiCallback>) -> Result<Self, anyhow::Error>
+    {
+        let window = Arc::new(egui_winit::create_window(ctx, event_loop, builder)?);
+        let size = window.inner_size();
+        let renderer = parent_renderer.create_shared(size.width.max(1), size.height.max(1), Arc::clone(&window))?;
+        let state = egui_winit::State::new(
+            ctx.clone(),
+            id,
+            &window,
+            Some(window.scale_factor() as f32),
+            None,
+            None
+        );
+        window.request_redraw();
+
+        Ok(Self { window, renderer, state, ui_cb, next_repaint: None, closed: false })
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    pub fn next_repaint(&self) -> Option<Instant> {
+        self.next_repaint
+    }
+
+    pub fn wake_if_due(&mut self, now: Instant) {
+        if let Some(next_repaint) = self.next_repaint && (next_repaint <= now) {
+            self.next_repaint = None;
+            self.window.request_redraw();
+        }
+    }
+
+    pub fn request_repaint_at(&mut self, when: Instant) {
+        self.next_repaint = Some(self.next_repaint.map_or(when, |t| t.min(when)));
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Applies the window commands egui sent to this viewport; returns whether it was asked to close
+    pub fn apply_commands(&mut self, commands: &[egui::ViewportCommand]) -> bool {
+        let pixels_per_point = egui_winit::pixels_per_point(self.state.egui_ctx(), &self.window);
+        self.closed |= super::apply_viewport_commands(&self.window, commands, pixels_per_point);
+        self.closed
+    }
+
+    pub fn set_ui_cb(&mut self, ui_cb: Arc<egui::DeferredViewportUiCallback>) {
+        self.ui_cb = ui_cb;
+    }
+
+    pub fn handle_window_event(&mut self, event: WindowEvent) {
+        if self.state.on_window_event(&self.window, &event).repaint {
+            self.window.request_redraw();
+        }
+
+        match event {
+            WindowEvent::CloseRequested => {
+                // let the UI decide whether to stop showing the viewport
+                let id = self.state.egui_input().viewport_id;
+                self.state.egui_input_mut().viewports.entry(id).or_default().events.push(egui::ViewportEvent::Close);
+            }
+            WindowEvent::Resized(size) if (size.width > 0) && (size.height > 0) => {
+                let screen = render::ScreenDescriptor {
+                    pixel_per_point: egui_winit::pixels_per_point(self.state.egui_ctx(), &self.window),
+                    screen_width: size.width,
+                    screen_height: size.height,
+                };
+                self.renderer.request_resize(&screen);
+            }
+            // egui-winit has taken the new scale for the next pass; the targets follow here in case no `Resized` comes with it
+            WindowEvent::ScaleFactorChanged { .. } => {
+                let size = self.window.inner_size();
+                if (size.width > 0) && (size.height > 0) {
+                    let screen = render::ScreenDescriptor {
+                        pixel_per_point: egui_winit::pixels_per_point(self.state.egui_ctx(), &self.window),
+                        screen_width: size.width,
+                        screen_height: size.height,
+                    };
+                    self.renderer.request_resize(&screen);
+                }
+                self.window.request_redraw();
+            }
+            WindowEvent::DroppedFile(_) => {
+                if let Some(file) = self.state.egui_input_mut().dropped_files.last_mut() {
+                    super::load_dropped_file(file);
+                }
+            }
+            WindowEvent::RedrawRequested => {
+                self.handle_redraw();
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_redraw(&mut self) {
+        let mut input = self.state.take_egui_input(&self.window);
+        egui_winit::update_viewport_info(input.viewports.entry(input.viewport_id).or_default(), self.state.egui_ctx(), &self.window, false);
+        let ui_cb = Arc::clone(&self.ui_cb);
+        let output = self.state.egui_ctx().run(input, |cx| ui_cb(cx));
+
+        self.state.handle_platform_output(&self.window, output.platform_output);
+        let triangles = self.state.egui_ctx().tessellate(output.shapes, output.pixels_per_point);
+        self.renderer.set_clear_color(self.state.egui_ctx().style().visuals.window_fill);
+
+        let id = self.state.egui_input().viewport_id;
+        // e.g. a "Close" button inside the viewport
+        if let Some(vp) = output.viewport_output.get(&id) && self.apply_commands(&vp.commands) {
+            return;
+        }
+        let repaint_delay = output.viewport_output.get(&id).map_or(Duration::MAX, |vp| vp.repaint_delay);
+        self.next_repaint = super::schedule_repaint(&self.window, repaint_delay);
+
+        let size = self.window.inner_size();
+        if (size.width == 0) || (size.height == 0) { return };
+
+        let screen = render::ScreenDescriptor {
+            pixel_per_point: output.pixels_per_point,
+            screen_width: size.width,
+            screen_height: size.height,
+        };
+
+        match self.renderer.render(&screen, &triangles, &output.textures_delta) {
+            Ok(_) => {},
+            Err(err @ (wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated)) => {
+                log::debug!("Skip a child viewport frame, the surface is reconfigured (reason: {err})");
+                self.renderer.request_resize(&screen);
+                self.window.request_redraw();
+            }
+            Err(wgpu::SurfaceError::Timeout) => {
+                log::debug!("Skip a child viewport frame, the surface texture timed out");
+                self.window.request_redraw();
+            }
+            Err(e) => log::error!("Unable to render child viewport (reason: {e})"),
+        }
+    }
+}
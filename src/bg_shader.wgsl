@@ -19,3 +19,20 @@ fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     return vec4<f32>(0.3, 0.2, 0.1, 1.0);
 }
+
+// solid fill of the viewport (the target rect), colored by the blend constant
+
+@vertex
+fn vs_fill(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    // one triangle covering the viewport
+    let x = f32(i32(index & 1u) * 4 - 1);
+    let y = f32(i32(index >> 1u) * 4 - 1);
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_fill(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}
@@ -85,6 +85,18 @@ fn position_from_screen(screen_pos: vec2<f32>) -> vec4<f32> {
     );
 }
 
+// Texture bindings
+
+@group(1) @binding(0) var r_tex_color: texture_2d<f32>;
+@group(1) @binding(1) var r_tex_sampler: sampler;
+
+struct TextureLocals {
+    /// size of the image / size of the texture (below 1 while the texture has room to grow)
+    uv_scale: vec2<f32>,
+    _padding: vec2<f32>,
+};
+@group(1) @binding(2) var<uniform> r_tex_locals: TextureLocals;
+
 @vertex
 fn vs_main(
     @location(0) a_pos: vec2<f32>,
@@ -92,31 +104,34 @@ fn vs_main(
     @location(2) a_color: u32,
 ) -> VertexOutput {
     var out: VertexOutput;
-    out.tex_coord = a_tex_coord;
+    // egui's UVs are relative to the image, which may take only part of the texture
+    out.tex_coord = a_tex_coord * r_tex_locals.uv_scale;
     out.color = unpack_color(a_color);
     out.position = position_from_screen(a_pos);
     return out;
 }
 
-// Fragment shader bindings
-
-@group(1) @binding(0) var r_tex_color: texture_2d<f32>;
-@group(1) @binding(1) var r_tex_sampler: sampler;
 
 fn sample_texture(in: VertexOutput) -> vec4<f32> {
     if r_locals.predictable_texture_filtering == 0 {
         // Hardware filtering: fast, but varies across GPUs and drivers.
-        return textureSample(r_tex_color, r_tex_sampler, in.tex_coord);
+        var tex_coord = in.tex_coord;
+        if any(r_tex_locals.uv_scale < vec2<f32>(1.0, 1.0)) {
+            // The sampler clamps at the texture's edge, so keep the filter footprint inside the image
+            let half_texel = 0.5 / vec2<f32>(textureDimensions(r_tex_color, 0));
+            tex_coord = clamp(tex_coord, half_texel, r_tex_locals.uv_scale - half_texel);
+        }
+        return textureSample(r_tex_color, r_tex_sampler, tex_coord);
     } else {
         // Manual bilinear filtering with four taps at pixel centers using textureLoad
-        let texture_size = vec2<i32>(textureDimensions(r_tex_color, 0));
-        let texture_size_f = vec2<f32>(texture_size);
+        let texture_size_f = vec2<f32>(textureDimensions(r_tex_color, 0));
         let pixel_coord = in.tex_coord * texture_size_f - 0.5;
         let pixel_fract = fract(pixel_coord);
         let pixel_floor = vec2<i32>(floor(pixel_coord));
 
-        // Manual texture clamping
-        let max_coord = texture_size - vec2<i32>(1, 1);
+        // Manual texture clamping (to the image, not to the room after it)
+        let image_size = vec2<i32>(round(texture_size_f * r_tex_locals.uv_scale));
+        let max_coord = image_size - vec2<i32>(1, 1);
         let p00 = clamp(pixel_floor + vec2<i32>(0, 0), vec2<i32>(0, 0), max_coord);
         let p10 = clamp(pixel_floor + vec2<i32>(1, 0), vec2<i32>(0, 0), max_coord);
         let p01 = clamp(pixel_floor + vec2<i32>(0, 1), vec2<i32>(0, 0), max_coord);
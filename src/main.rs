@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use egui_demo::app;
 use winit::event_loop::EventLoop;
 
@@ -7,7 +9,11 @@ fn main() -> Result<(), anyhow::Error> {
 }
 
 async fn run() ->  Result<(), anyhow::Error> {
-    let event_loop = EventLoop::new()?;
-    event_loop.run_app(&mut app::App::new())?;
+    let event_loop = EventLoop::<app::UserEvent>::with_user_event().build()?;
+    let mut app = app::App::new()
+        .with_event_loop_proxy(event_loop.create_proxy())
+        .with_settings_path(Some(PathBuf::from("egui-demo.json")))
+    ;
+    event_loop.run_app(&mut app)?;
     Ok(())
 }
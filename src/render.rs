@@ -1,15 +1,31 @@
-use std::num::NonZeroU64;
+use std::{cell::RefCell, num::NonZeroU64, rc::Rc, sync::{Arc, atomic::{AtomicBool, Ordering}}};
 
 use egui::epaint::Vertex;
-use wgpu::{SurfaceTargetUnsafe, rwh::{HandleError, HasDisplayHandle, HasWindowHandle, RawDisplayHandle, RawWindowHandle}, util::DeviceExt};
+use wgpu::util::DeviceExt;
+#[cfg(feature = "raw-handle")]
+use wgpu::{SurfaceTargetUnsafe, rwh::{HandleError, HasDisplayHandle, HasWindowHandle, RawDisplayHandle, RawWindowHandle}};
 
 mod buffer;
+mod callback;
+mod error;
+mod mipmap;
+mod retained;
+mod staging;
 mod texture;
+mod timer;
 
+pub use callback::{Callback, CallbackInfo, CallbackTrait};
+pub use error::{ReadbackError, RendererError};
+pub use retained::RetainedMeshes;
+
+/// Raw handles of a window owned elsewhere, e.g. when embedding into a host application that only hands out handles.
+/// Prefer passing the window itself (e.g. an `Arc<Window>`), which keeps it alive as long as the surface.
+#[cfg(feature = "raw-handle")]
 pub struct RawWindow {
     display_handle: RawDisplayHandle,
     window_handle: RawWindowHandle,
 }
+#[cfg(feature = "raw-handle")]
 impl RawWindow {
     pub fn create<T: HasDisplayHandle + HasWindowHandle + 'static>(target: &T) -> Result<Self, HandleError> {
         Ok(RawWindow {
@@ -18,76 +34,562 @@ impl RawWindow {
         })
     }
 }
+#[cfg(feature = "raw-handle")]
 impl From<&RawWindow> for SurfaceTargetUnsafe {
     fn from(value: &RawWindow) -> Self {
         Self::RawHandle{ raw_display_handle: value.display_handle, raw_window_handle: value.window_handle }
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct ScreenDescriptor {
     pub pixel_per_point: f32,
     pub screen_width: u32,
     pub screen_height: u32,
 }
 
+/// What a rendered frame submitted to the GPU
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    /// `draw_indexed` calls issued for egui meshes (paint callbacks not included)
+    pub draw_calls: u32,
+    pub vertices: u32,
+    pub indices: u32,
+    /// full and partial texture uploads
+    pub textures_uploaded: u32,
+    /// uploads that needed a new texture (and bind group), e.g. a new image or a grown font atlas
+    pub textures_allocated: u32,
+    /// GPU time of the render pass, measured a frame or more earlier
+    /// (`None` without `TIMESTAMP_QUERY` support)
+    pub gpu_time_ms: Option<f32>,
+}
+
+impl FrameStats {
+    /// Adds the counts of another egui context drawn into the same frame
+    fn add(&mut self, other: &FrameStats) {
+        self.vertices += other.vertices;
+        self.indices += other.indices;
+        self.textures_uploaded += other.textures_uploaded;
+        self.textures_allocated += other.textures_allocated;
+    }
+}
+
+/// Another egui context drawn into part of the frame by [`WgpuRenderer::render_with_panes`], e.g. one side of a split view
+pub struct Pane<'a> {
+    /// Made by [`WgpuRenderer::create_pane`]; keeps the textures of this context apart from the others
+    pub renderer: &'a mut WgpuRenderer,
+    /// Top-left corner of the pane in the frame (physical pixels)
+    pub origin: [u32; 2],
+    /// Size and scale of the pane, not of the whole frame
+    pub screen: ScreenDescriptor,
+    pub triangles: &'a [egui::ClippedPrimitive],
+    pub images: &'a egui::TexturesDelta,
+}
+
+/// Uploaded data of one egui context, drawn later in the frame's render pass
+struct PreparedLayer<'a> {
+    screen: ScreenDescriptor,
+    commands: Vec<buffer::DrawCommand<'a>>,
+    /// offset of the indices in the mesh buffer (`None` without meshes)
+    index_start: Option<u64>,
+    stats: FrameStats,
+}
+
+/// What is drawn behind the egui meshes
+#[derive(Clone, Copy, Debug)]
+pub enum Background {
+    SolidColor(wgpu::Color),
+    /// Full-screen shader of `bg_shader.wgsl`
+    Shader,
+    /// Cleared to transparent, e.g. to be composited over another scene
+    None,
+    /// Not cleared, egui is drawn over what the target already contains (e.g. an engine's frame).
+    /// Only meaningful without MSAA and supersampling, since the intermediate textures have no previous contents.
+    Keep,
+}
+
+#[derive(Clone)]
+pub struct RendererConfig {
+    /// Overridable by `WGPU_BACKEND` (e.g. `vulkan`, `metal`, `dx12`, `gl`)
+    pub backends: wgpu::Backends,
+    /// `HighPerformance` picks the discrete GPU on laptops.
+    /// Overridable by `WGPU_POWER_PREF` (`low`, `high` or `none`)
+    pub power_preference: wgpu::PowerPreference,
+    /// MSAA sample count (1 disables multisampling)
+    pub sample_count: u32,
+    /// Renders egui at this multiple of the frame size and downsamples the result into the frame
+    /// (e.g. 2.0 for smoother thin lines and text in screenshots). 1.0 renders directly into the frame.
+    /// Scales above 2.0 skip source pixels when downsampling, so they alias again.
+    pub render_scale: f32,
+    /// Falls back to `Fifo` (always supported) when the surface does not support it
+    pub present_mode: wgpu::PresentMode,
+    /// Frames the CPU may queue ahead of the display. 1 shows input soonest (e.g. pen drawing) but stalls
+    /// whenever a frame takes longer than a refresh; 3 absorbs such spikes at the cost of one more frame of lag.
+    pub max_frame_latency: u32,
+    /// [`WgpuRenderer::render`] blocks until the GPU has finished the frame, so the next frame samples input
+    /// as late as possible. Lowers input-to-photon latency (best with `max_frame_latency` 1) but costs CPU time.
+    pub low_latency: bool,
+    pub background: Background,
+    /// Prefers a pre/post-multiplied alpha surface so that a transparent window shows through.
+    /// [`Background::Shader`] is replaced by [`Background::None`] then.
+    pub transparent: bool,
+    /// Adds noise below one 8-bit step to the output so that gradients do not band
+    pub dithering: bool,
+    /// Bilinear filtering done in the shader instead of the sampler, so that text looks the same on every GPU
+    /// (e.g. for snapshot images). The filter mode of `TextureOptions` is ignored then.
+    pub predictable_texture_filtering: bool,
+    /// Validation and debug flags of the instance. Validation is on in debug builds;
+    /// overridable by `WGPU_VALIDATION`, `WGPU_DEBUG` and `WGPU_GPU_BASED_VALIDATION` (`0` disables)
+    pub instance_flags: wgpu::InstanceFlags,
+    /// Extra device features, e.g. for paint callbacks or debugging
+    pub required_features: wgpu::Features,
+    /// Attaches a depth buffer of this format (e.g. `Depth32Float`) to the render pass, so that 3D paint callbacks can depth-test.
+    /// It is cleared to 1.0 every frame and shared by all callbacks; the egui meshes neither test nor write it.
+    pub depth_format: Option<wgpu::TextureFormat>,
+    /// Anisotropic filtering level (1..=16) of linearly filtered textures, which keeps them sharp when minified at an angle
+    /// (e.g. tilted imagery in a 3D callback). 1 disables it, as do adapters that cannot filter anisotropically.
+    pub anisotropy: u16,
+    /// Prefers an `Rgba16Float` surface where the surface supports it, so that paint callbacks can output values above 1.0
+    /// (extended range, shown brighter than white on HDR displays). Falls back to an 8-bit sRGB surface otherwise.
+    /// egui's own colors stay SDR; only callbacks benefit.
+    pub hdr: bool,
+    /// Vertices and indices the mesh buffer holds before it first has to grow (to twice what a frame needs).
+    /// A UI known to be large can start big enough to avoid reallocating on the first frames.
+    pub initial_vertex_capacity: usize,
+    pub initial_index_capacity: usize,
+}
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::PRIMARY.with_env(),
+            power_preference: wgpu::PowerPreference::from_env().unwrap_or_default(),
+            sample_count: 1,
+            render_scale: 1.0,
+            present_mode: wgpu::PresentMode::Fifo,
+            max_frame_latency: 2,
+            low_latency: false,
+            background: Background::Shader,
+            transparent: false,
+            dithering: true,
+            predictable_texture_filtering: false,
+            instance_flags: wgpu::InstanceFlags::from_build_config().with_env(),
+            required_features: wgpu::Features::empty(),
+            depth_format: None,
+            anisotropy: 1,
+            hdr: false,
+            initial_vertex_capacity: 1024,
+            initial_index_capacity: 1024 * 3,
+        }
+    }
+}
+
+/// Chainable alternative to [`WgpuRenderer::create_with_config`]
+pub struct WgpuRendererBuilder {
+    frame_width: u32,
+    frame_height: u32,
+    config: RendererConfig,
+    clear_color: Option<egui::Color32>,
+}
+impl WgpuRendererBuilder {
+    pub fn new(frame_width: u32, frame_height: u32) -> Self {
+        Self { frame_width, frame_height, config: RendererConfig::default(), clear_color: None }
+    }
+
+    pub fn config(mut self, config: RendererConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn backends(mut self, backends: wgpu::Backends) -> Self {
+        self.config.backends = backends;
+        self
+    }
+
+    pub fn power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.config.power_preference = power_preference;
+        self
+    }
+
+    pub fn present_mode(mut self, present_mode: wgpu::PresentMode) -> Self {
+        self.config.present_mode = present_mode;
+        self
+    }
+
+    pub fn max_frame_latency(mut self, max_frame_latency: u32) -> Self {
+        self.config.max_frame_latency = max_frame_latency;
+        self
+    }
+
+    pub fn low_latency(mut self, low_latency: bool) -> Self {
+        self.config.low_latency = low_latency;
+        self
+    }
+
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.config.sample_count = sample_count;
+        self
+    }
+
+    pub fn render_scale(mut self, render_scale: f32) -> Self {
+        self.config.render_scale = render_scale;
+        self
+    }
+
+    pub fn background(mut self, background: Background) -> Self {
+        self.config.background = background;
+        self
+    }
+
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.config.transparent = transparent;
+        self
+    }
+
+    pub fn dithering(mut self, dithering: bool) -> Self {
+        self.config.dithering = dithering;
+        self
+    }
+
+    pub fn predictable_texture_filtering(mut self, predictable: bool) -> Self {
+        self.config.predictable_texture_filtering = predictable;
+        self
+    }
+
+    pub fn depth_format(mut self, depth_format: Option<wgpu::TextureFormat>) -> Self {
+        self.config.depth_format = depth_format;
+        self
+    }
+
+    pub fn anisotropy(mut self, anisotropy: u16) -> Self {
+        self.config.anisotropy = anisotropy;
+        self
+    }
+
+    pub fn hdr(mut self, hdr: bool) -> Self {
+        self.config.hdr = hdr;
+        self
+    }
+
+    pub fn initial_vertex_capacity(mut self, vertices: usize) -> Self {
+        self.config.initial_vertex_capacity = vertices;
+        self
+    }
+
+    pub fn initial_index_capacity(mut self, indices: usize) -> Self {
+        self.config.initial_index_capacity = indices;
+        self
+    }
+
+    /// See [`WgpuRenderer::set_clear_color`]
+    pub fn clear_color(mut self, color: egui::Color32) -> Self {
+        self.clear_color = Some(color);
+        self
+    }
+
+    pub async fn build(self, target: impl Into<wgpu::SurfaceTarget<'static>>) -> Result<WgpuRenderer, RendererError> {
+        let renderer = WgpuRenderer::create_with_config(self.frame_width, self.frame_height, target, &self.config).await?;
+        Ok(self.finish(renderer))
+    }
+
+    /// # Safety
+    /// See [`WgpuRenderer::create_with_raw_handle`]
+    #[cfg(feature = "raw-handle")]
+    pub async unsafe fn build_with_raw_handle(self, target: &RawWindow) -> Result<WgpuRenderer, RendererError> {
+        let renderer = unsafe { WgpuRenderer::create_with_raw_handle(self.frame_width, self.frame_height, target, &self.config).await? };
+        Ok(self.finish(renderer))
+    }
+
+    pub async fn build_headless(self) -> Result<WgpuRenderer, RendererError> {
+        let renderer = WgpuRenderer::create_headless_with_config(self.frame_width, self.frame_height, &self.config).await?;
+        Ok(self.finish(renderer))
+    }
+
+    fn finish(&self, mut renderer: WgpuRenderer) -> WgpuRenderer {
+        if let Some(color) = self.clear_color {
+            renderer.set_clear_color(color);
+        }
+        renderer
+    }
+}
+
+/// Receives the pixels of a presented frame
+type ScreenshotFn = Box<dyn FnOnce(egui::ColorImage)>;
+
+/// Objects made once per device and surface format, which the renderers made by
+/// [`WgpuRenderer::create_shared`] and [`WgpuRenderer::create_pane`] share with the one they are made from
+#[derive(Clone)]
+struct SharedObjects {
+    texture_layout: wgpu::BindGroupLayout,
+    uniform_layout: wgpu::BindGroupLayout,
+    texture_fallback: wgpu::BindGroup,
+    bg_pipeline: wgpu::RenderPipeline,
+    /// fills the target rect with the background color
+    fill_pipeline: wgpu::RenderPipeline,
+    fg_pipeline: wgpu::RenderPipeline,
+    mipmaps: mipmap::MipmapGenerator,
+    /// blits the supersampled frame into the target (`None` at render scale 1.0)
+    downsampler: Option<mipmap::MipmapGenerator>,
+}
+
 pub struct WgpuRenderer {
-    surface: wgpu::Surface<'static>,
+    /// `None` for [`WgpuRenderer::from_existing`], which does not know how the device was made
+    instance: Option<wgpu::Instance>,
+    adapter: Option<wgpu::Adapter>,
+    adapter_info: Option<wgpu::AdapterInfo>,
+    surface: Option<wgpu::Surface<'static>>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
-    texture_layout: wgpu::BindGroupLayout,
-    texture_fallback: wgpu::BindGroup,
+    /// settings the renderer was created with, reused by `recover_device`
+    renderer_config: RendererConfig,
+    sample_count: u32,
+    msaa_texture: Option<wgpu::Texture>,
+    depth_format: Option<wgpu::TextureFormat>,
+    /// `None` without a depth format
+    depth_texture: Option<wgpu::Texture>,
+    render_scale: f32,
+    /// frame drawn at `render_scale` (`None` at 1.0)
+    supersampled_texture: Option<wgpu::Texture>,
+    shared: SharedObjects,
     uniform: wgpu::BindGroup,
     uniform_buffer: wgpu::Buffer,
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    bg_pipeline: wgpu::RenderPipeline,
-    fg_pipeline: wgpu::RenderPipeline,
-    samplers: egui::ahash::HashMap<egui::TextureOptions, wgpu::Sampler>,
-    texture_cache: egui::ahash::HashMap<egui::TextureId, texture::TextureResource>,
+    mesh_buffer: wgpu::Buffer,
+    background: Background,
+    clear_color: wgpu::Color,
+    dithering: bool,
+    predictable_texture_filtering: bool,
+    /// part of the frame the own UI is drawn into, in physical pixels (`None` = the whole frame)
+    target_rect: Option<(u32, u32, u32, u32)>,
+    textures: Rc<RefCell<texture::TextureStore>>,
+    /// meshes uploaded once, drawn in place of [`RetainedMeshes`] callbacks
+    retained: egui::IdMap<retained::RetainedLayer>,
+    timer: Option<timer::GpuTimer>,
+    low_latency: bool,
+    /// receives the next frame presented by `render`
+    screenshot: Option<ScreenshotFn>,
+    /// frames copied for screenshots, handed over once their copy has finished
+    pending_screenshots: Vec<(texture::Readback, ScreenshotFn)>,
+    device_lost: Arc<AtomicBool>,
 }
 impl WgpuRenderer {
-    pub async fn create(frame_width: u32, framw_height: u32, target: &RawWindow) -> Result<Self, anyhow::Error> {
-        assert!(frame_width > 0 && framw_height > 0, "wgpu does nou allow size 0.");
+    pub fn builder(frame_width: u32, frame_height: u32) -> WgpuRendererBuilder {
+        WgpuRendererBuilder::new(frame_width, frame_height)
+    }
 
-        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::PRIMARY,
-            ..Default::default()
-        });
+    /// `target` is usually an `Arc<Window>`, which the surface keeps alive
+    pub async fn create(frame_width: u32, framw_height: u32, target: impl Into<wgpu::SurfaceTarget<'static>>) -> Result<Self, RendererError> {
+        Self::builder(frame_width, framw_height).build(target).await
+    }
+
+    pub async fn create_with_config(frame_width: u32, framw_height: u32, target: impl Into<wgpu::SurfaceTarget<'static>>, renderer_config: &RendererConfig) -> Result<Self, RendererError> {
+        if (frame_width == 0) || (framw_height == 0) {
+            return Err(RendererError::ZeroSize);
+        }
+
+        let instance = make_instance(renderer_config);
+        let surface = instance.create_surface(target)?;
+        Self::create_with_surface(instance, surface, frame_width, framw_height, renderer_config).await
+    }
 
+    /// Creates a renderer for a window known only by its raw handles.
+    ///
+    /// # Safety
+    /// The window must stay alive (and its handles valid) until the renderer is dropped.
+    #[cfg(feature = "raw-handle")]
+    pub async unsafe fn create_with_raw_handle(frame_width: u32, framw_height: u32, target: &RawWindow, renderer_config: &RendererConfig) -> Result<Self, RendererError> {
+        if (frame_width == 0) || (framw_height == 0) {
+            return Err(RendererError::ZeroSize);
+        }
+
+        let instance = make_instance(renderer_config);
         let surface = unsafe { instance.create_surface_unsafe(target.into())? };
-        let adapter = instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::default(),
-            force_fallback_adapter: false,
-            compatible_surface: Some(&surface),
-        }).await?;
+        Self::create_with_surface(instance, surface, frame_width, framw_height, renderer_config).await
+    }
+
+    async fn create_with_surface(instance: wgpu::Instance, surface: wgpu::Surface<'static>, frame_width: u32, framw_height: u32, renderer_config: &RendererConfig) -> Result<Self, RendererError> {
+        let adapter = request_adapter(&instance, renderer_config.power_preference, Some(&surface)).await
+            .inspect_err(|_| log::error!("No adapter found for backends {:?}", renderer_config.backends))?
+        ;
+
+        let caps = surface.get_capabilities(&adapter);
+        let hdr_format = renderer_config.hdr.then(|| caps.formats.iter().find(|fmt| **fmt == wgpu::TextureFormat::Rgba16Float)).flatten();
+        if renderer_config.hdr && hdr_format.is_none() {
+            log::warn!("HDR surface is not supported (formats: {:?}), use an sRGB format", caps.formats);
+        }
+        let surface_format = hdr_format
+            .or_else(|| caps.formats.iter().find(|fmt| fmt.is_srgb()))
+            .copied()
+            .unwrap_or(caps.formats[0])
+        ;
+
+        let present_mode = if caps.present_modes.contains(&renderer_config.present_mode) {
+            renderer_config.present_mode
+        } else {
+            log::warn!("Present mode {:?} is not supported, use Fifo", renderer_config.present_mode);
+            wgpu::PresentMode::Fifo
+        };
+
+        let mut renderer_config = renderer_config.clone();
+        let alpha_mode = if renderer_config.transparent {
+            let alpha_mode = [wgpu::CompositeAlphaMode::PreMultiplied, wgpu::CompositeAlphaMode::PostMultiplied].into_iter()
+                .find(|mode| caps.alpha_modes.contains(mode))
+            ;
+            if alpha_mode.is_none() {
+                log::warn!("Transparent surface is not supported (alpha modes: {:?})", caps.alpha_modes);
+            }
+            if let Background::Shader = renderer_config.background {
+                renderer_config.background = Background::None;
+            }
+            alpha_mode.unwrap_or(caps.alpha_modes[0])
+        } else {
+            caps.alpha_modes[0]
+        };
+
+        let config = wgpu::SurfaceConfiguration {
+            // COPY_SRC for request_screenshot where the surface allows it
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (caps.usages & wgpu::TextureUsages::COPY_SRC),
+            format: surface_format,
+            width: frame_width,
+            height: framw_height,
+            present_mode,
+            desired_maximum_frame_latency: renderer_config.max_frame_latency.max(1),
+            alpha_mode,
+            view_formats: vec![],
+        };
+
+        Self::create_internal(instance, adapter, Some(surface), config, &renderer_config).await
+    }
+
+    /// Creates a renderer without a window (e.g. for CI).
+    /// Only offscreen rendering ([`WgpuRenderer::render_to_texture`]) is available;
+    /// [`WgpuRenderer::render`] fails and surface reconfiguration is skipped.
+    pub async fn create_headless(frame_width: u32, framw_height: u32) -> Result<Self, RendererError> {
+        Self::builder(frame_width, framw_height).build_headless().await
+    }
+
+    pub async fn create_headless_with_config(frame_width: u32, framw_height: u32, renderer_config: &RendererConfig) -> Result<Self, RendererError> {
+        if (frame_width == 0) || (framw_height == 0) {
+            return Err(RendererError::ZeroSize);
+        }
+
+        let instance = make_instance(renderer_config);
+
+        let adapter = request_adapter(&instance, renderer_config.power_preference, None).await
+            .inspect_err(|_| log::error!("No adapter found for backends {:?}", renderer_config.backends))?
+        ;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: frame_width,
+            height: framw_height,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: renderer_config.max_frame_latency.max(1),
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+
+        Self::create_internal(instance, adapter, None, config, renderer_config).await
+    }
+
+    async fn create_internal(instance: wgpu::Instance, adapter: wgpu::Adapter, surface: Option<wgpu::Surface<'static>>, config: wgpu::SurfaceConfiguration, renderer_config: &RendererConfig) -> Result<Self, RendererError> {
+        let adapter_info = adapter.get_info();
+        log::info!("Adapter: {} ({:?}, {:?}, driver: {} {}, power preference: {:?})", adapter_info.name, adapter_info.backend, adapter_info.device_type, adapter_info.driver, adapter_info.driver_info, renderer_config.power_preference);
 
         let (device, queue) = adapter.request_device(&wgpu::DeviceDescriptor {
             label: None,
-            required_features: wgpu::Features::empty(),
+            // GPU timing in the frame stats whenever the adapter can do it
+            required_features: renderer_config.required_features | (adapter.features() & wgpu::Features::TIMESTAMP_QUERY),
             required_limits: wgpu::Limits::default(),
             experimental_features: wgpu::ExperimentalFeatures::disabled(),
             memory_hints: Default::default(),
-            trace: wgpu::Trace::Off,
+            trace: device_trace(),
         }).await?;
 
-        let caps = surface.get_capabilities(&adapter);
-        let surface_format = caps.formats.iter()
-            .find(|fmt| fmt.is_srgb())
-            .copied()
-            .unwrap_or(caps.formats[0])
-        ;
+        // e.g. driver reset (TDR) or resuming from hibernation
+        let device_lost = Arc::new(AtomicBool::new(false));
+        device.set_device_lost_callback({
+            let device_lost = Arc::clone(&device_lost);
+            move |reason, message| {
+                if let wgpu::DeviceLostReason::Unknown = reason {
+                    log::error!("GPU device lost (reason: {message})");
+                    device_lost.store(true, Ordering::Relaxed);
+                }
+            }
+        });
+
+        if let Some(surface) = &surface {
+            surface.configure(&device, &config);
+        }
 
+        let sample_count = if adapter.get_texture_format_features(config.format).flags.sample_count_supported(renderer_config.sample_count) {
+            renderer_config.sample_count
+        } else {
+            log::warn!("MSAA sample count {} is not supported, use 1", renderer_config.sample_count);
+            1
+        };
+
+        let anisotropy = match adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING) {
+            true => renderer_config.anisotropy,
+            false => {
+                if renderer_config.anisotropy > 1 {
+                    log::warn!("Anisotropic filtering is not supported, use 1");
+                }
+                1
+            }
+        };
+
+        let mut renderer = Self::from_device(device, queue, surface, config, &RendererConfig { sample_count, anisotropy, ..renderer_config.clone() });
+        // as asked for, so that a recovered device on another adapter gets another try
+        renderer.renderer_config = renderer_config.clone();
+        renderer.instance = Some(instance);
+        renderer.adapter = Some(adapter);
+        renderer.adapter_info = Some(adapter_info);
+        renderer.device_lost = device_lost;
+        Ok(renderer)
+    }
+
+    /// Creates a renderer on a device owned by the caller, e.g. to draw egui as an overlay of an existing wgpu engine.
+    /// No adapter or surface is created; frames are drawn with [`WgpuRenderer::render_to_view`].
+    ///
+    /// The caller keeps handling device loss, and [`WgpuRenderer::create_shared`] and
+    /// [`WgpuRenderer::recover_device`] are not available. The background defaults to [`Background::Keep`].
+    pub fn from_existing(device: wgpu::Device, queue: wgpu::Queue, surface_format: wgpu::TextureFormat) -> Self {
+        let renderer_config = RendererConfig {
+            sample_count: 1,
+            background: Background::Keep,
+            ..RendererConfig::default()
+        };
+        Self::from_existing_with_config(device, queue, surface_format, &renderer_config)
+    }
+
+    /// [`WgpuRenderer::from_existing`] with the given settings. Without an adapter to ask, `sample_count` must be one
+    /// the device supports for `surface_format`; the instance and adapter settings (e.g. `backends`) are ignored.
+    pub fn from_existing_with_config(device: wgpu::Device, queue: wgpu::Queue, surface_format: wgpu::TextureFormat, renderer_config: &RendererConfig) -> Self {
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
-            width: frame_width,
-            height: framw_height,
-            present_mode: caps.present_modes[0],
-            desired_maximum_frame_latency: 2,
-            alpha_mode: caps.alpha_modes[0],
+            width: 1,
+            height: 1,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: renderer_config.max_frame_latency.max(1),
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
         };
+        Self::from_device(device, queue, None, config, renderer_config)
+    }
+
+    fn from_device(device: wgpu::Device, queue: wgpu::Queue, surface: Option<wgpu::Surface<'static>>, config: wgpu::SurfaceConfiguration, renderer_config: &RendererConfig) -> Self {
+        let sample_count = renderer_config.sample_count;
+        let depth_format = renderer_config.depth_format.filter(|format| {
+            let is_depth = format.has_depth_aspect();
+            if !is_depth {
+                log::warn!("{format:?} is not a depth format, no depth buffer is attached");
+            }
+            is_depth
+        });
+        // the most wgpu accepts
+        let anisotropy = renderer_config.anisotropy.clamp(1, 16);
 
         let texture_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("texture bind group layout"),
@@ -110,6 +612,16 @@ impl WgpuRenderer {
                     count: None,
 
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(size_of::<texture::TextureLocals>() as _),
+                    },
+                    count: None,
+                },
             ]
         });
         let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -128,115 +640,823 @@ impl WgpuRenderer {
             ],
         });
 
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform buffer"),
-            contents: bytemuck::cast_slice(&[
-                buffer::UniformBuffer {
-                    screen_size_optionts: [0.0, 0.0],
-                    dithering: 0,
-                    predicatable_texture_fintering: 0,
-                }
-            ]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-        let uniform = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Uniform bind group"),
-            layout: &uniform_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &uniform_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                }
-            ],
-        });
+        let linear_sampler = texture::into_sampler(&device, egui::TextureOptions::LINEAR, anisotropy, Some("Texture sampler fallback"));
+        let buffer_fallback = texture::into_texture(&device, wgpu::Extent3d{ width: 1, height: 1, depth_or_array_layers: 1 }, wgpu::TextureFormat::Rgba8Unorm, 1, Some("Texture image fallback"));
+        let locals_fallback = texture::into_texture_locals(&device, buffer_fallback.size(), buffer_fallback.size());
+        let texture_fallback = texture::into_bind_group(&device, &texture_layout, &buffer_fallback, &linear_sampler, &locals_fallback, Some("texture bind group fallback"));
+
+        let render_scale = if renderer_config.render_scale.is_finite() && (renderer_config.render_scale > 0.0) {
+            renderer_config.render_scale
+        } else {
+            log::warn!("Render scale {} is not positive, use 1.0", renderer_config.render_scale);
+            1.0
+        };
 
-        let linear_sampler = texture::into_sampler(&device, egui::TextureOptions::LINEAR, Some("Texture sampler fallback"));
-        let buffer_fallback = texture::into_texture(&device, wgpu::Extent3d{ width: 1, height: 1, depth_or_array_layers: 1 }, Some("Texture image fallback"));
-        let texture_fallback = texture::into_bind_group(&device, &texture_layout, &buffer_fallback, &linear_sampler, Some("texture bind group fallback"));
+        let shared = SharedObjects {
+            bg_pipeline: make_background_pipeline(&device, &config, sample_count, depth_format, false),
+            fill_pipeline: make_background_pipeline(&device, &config, sample_count, depth_format, true),
+            fg_pipeline: make_freground_pipeline(&device, &config, sample_count, depth_format, &[&uniform_layout, &texture_layout]),
+            texture_layout,
+            uniform_layout,
+            texture_fallback,
+            mipmaps: mipmap::MipmapGenerator::new(&device, wgpu::TextureFormat::Rgba8Unorm),
+            downsampler: (render_scale != 1.0).then(|| mipmap::MipmapGenerator::new(&device, config.format)),
+        };
+        let textures = make_texture_store(&device, anisotropy);
+        let renderer_config = RendererConfig { depth_format, render_scale, anisotropy, ..renderer_config.clone() };
 
-        let vertex_buffer = buffer::make_vertex_buffer(&device, size_of::<Vertex>() as u64 * 1024);
-        let index_buffer = buffer::make_index_buffer(&device, size_of::<u32>() as u64 * 1024 * 3);
+        let mut renderer = Self::new_internal(device, queue, surface, config, &renderer_config, shared, textures);
+        renderer.recreate_size_dependent();
+        renderer
+    }
 
-        let bg_pipeline = make_background_pipeline(&device, &config);
-        let fg_pipeline = make_freground_pipeline(&device, &config, &[&uniform_layout, &texture_layout]);
+    /// The one place the fields are set up: with the objects `from_device` has just made,
+    /// or with those of the renderer that `create_shared` and `create_pane` are called on.
+    /// `renderer_config` holds the settings in effect (e.g. a sample count the adapter supports).
+    fn new_internal(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface: Option<wgpu::Surface<'static>>,
+        config: wgpu::SurfaceConfiguration,
+        renderer_config: &RendererConfig,
+        shared: SharedObjects,
+        textures: Rc<RefCell<texture::TextureStore>>) -> Self
+    {
+        let (uniform_buffer, uniform) = make_uniform(&device, &shared.uniform_layout);
+        let mesh_capacity = (renderer_config.initial_vertex_capacity, renderer_config.initial_index_capacity);
+        let mesh_buffer = buffer::make_mesh_buffer(&device, mesh_buffer_size(mesh_capacity));
+        let timer = timer::GpuTimer::new(&device, &queue);
 
-        Ok(Self {
+        Self {
+            instance: None,
+            adapter: None,
+            adapter_info: None,
             surface,
             device,
             queue,
             config,
-            texture_layout,
-            texture_fallback,
+            renderer_config: renderer_config.clone(),
+            sample_count: renderer_config.sample_count,
+            msaa_texture: None,
+            depth_format: renderer_config.depth_format,
+            depth_texture: None,
+            render_scale: renderer_config.render_scale,
+            supersampled_texture: None,
+            shared,
             uniform,
             uniform_buffer,
-            vertex_buffer,
-            index_buffer,
-            bg_pipeline,
-            fg_pipeline,
-            samplers: [(egui::TextureOptions::LINEAR, linear_sampler)].into_iter().collect(),
-            texture_cache: egui::ahash::HashMap::default(),
-        })
+            mesh_buffer,
+            background: renderer_config.background,
+            clear_color: wgpu::Color{ r: 0.1, g: 0.2, b: 0.3, a: 1.0 },
+            dithering: renderer_config.dithering,
+            predictable_texture_filtering: renderer_config.predictable_texture_filtering,
+            target_rect: None,
+            textures,
+            retained: egui::IdMap::default(),
+            timer,
+            low_latency: renderer_config.low_latency,
+            screenshot: None,
+            pending_screenshots: Vec::new(),
+            device_lost: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Settings in effect: as created, adjusted to the adapter and changed since
+    fn current_config(&self) -> RendererConfig {
+        RendererConfig {
+            sample_count: self.sample_count,
+            depth_format: self.depth_format,
+            render_scale: self.render_scale,
+            anisotropy: self.anisotropy(),
+            background: self.background,
+            dithering: self.dithering,
+            ..self.renderer_config.clone()
+        }
     }
 
+    /// Creates a renderer for another window that shares the device, pipelines and textures with `self`.
+    /// Used for egui child viewports, which receive only the texture deltas of their own passes.
+    pub fn create_shared(&self, frame_width: u32, framw_height: u32, target: impl Into<wgpu::SurfaceTarget<'static>>) -> Result<Self, RendererError> {
+        if (frame_width == 0) || (framw_height == 0) {
+            return Err(RendererError::ZeroSize);
+        }
+
+        let (Some(instance), Some(adapter)) = (&self.instance, &self.adapter) else { return Err(RendererError::ExternalDevice) };
+        let surface = instance.create_surface(target)?;
+        let caps = surface.get_capabilities(adapter);
+        if !caps.formats.contains(&self.config.format) {
+            return Err(RendererError::UnsupportedSurfaceFormat(self.config.format));
+        }
+
+        let config = wgpu::SurfaceConfiguration {
+            width: frame_width,
+            height: framw_height,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (caps.usages & wgpu::TextureUsages::COPY_SRC),
+            ..self.config.clone()
+        };
+        surface.configure(&self.device, &config);
+
+        let mut renderer = Self::new_internal(self.device.clone(), self.queue.clone(), Some(surface), config, &self.current_config(), self.shared.clone(), Rc::clone(&self.textures));
+        renderer.instance = self.instance.clone();
+        renderer.adapter = self.adapter.clone();
+        renderer.adapter_info = self.adapter_info.clone();
+        renderer.clear_color = self.clear_color;
+        renderer.device_lost = Arc::clone(&self.device_lost);
+        renderer.recreate_size_dependent();
+        Ok(renderer)
+    }
+
+    /// Creates a renderer for another egui context drawn into the frames of `self` (see [`Pane`]).
+    /// It shares the device and pipelines, but keeps its own textures since every context starts its ids from the same values.
+    /// Like [`WgpuRenderer::create_shared`], it keeps the device when `self` recovers a lost one (see [`WgpuRenderer::recover_pane`]).
+    pub fn create_pane(&self) -> Self {
+        let renderer_config = RendererConfig {
+            // the frame it is drawn into is already supersampled
+            render_scale: 1.0,
+            low_latency: false,
+            ..self.current_config()
+        };
+        let shared = SharedObjects { downsampler: None, ..self.shared.clone() };
+
+        // a pane draws into the pass of `self`, which owns the MSAA, depth and supersampled targets
+        let mut renderer = Self::new_internal(self.device.clone(), self.queue.clone(), None, self.config.clone(), &renderer_config, shared, make_texture_store(&self.device, self.anisotropy()));
+        renderer.instance = self.instance.clone();
+        renderer.adapter = self.adapter.clone();
+        renderer.adapter_info = self.adapter_info.clone();
+        renderer.clear_color = self.clear_color;
+        renderer.timer = None;
+        renderer.device_lost = Arc::clone(&self.device_lost);
+        renderer
+    }
+
+    /// Recreates a renderer made by [`WgpuRenderer::create_pane`] after `parent` recovered its device,
+    /// re-uploading the textures egui has sent so far.
+    pub fn recover_pane(&mut self, parent: &WgpuRenderer) {
+        let backups = std::mem::take(&mut self.textures.borrow_mut().backups);
+        let retained = std::mem::take(&mut self.retained);
+        *self = parent.create_pane();
+
+        let images = backups.into_iter().collect::<Vec<_>>();
+        self.upload_textures(&images);
+        for (id, layer) in retained {
+            self.retain_meshes(id, layer.triangles);
+        }
+    }
+
+    /// GPU, backend and driver the renderer runs on (worth including in bug reports).
+    /// `None` for [`WgpuRenderer::from_existing`].
+    pub fn adapter_info(&self) -> Option<&wgpu::AdapterInfo> {
+        self.adapter_info.as_ref()
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    /// Color target format that paint callback pipelines must match (`Rgba16Float` with [`RendererConfig::hdr`] where supported)
+    pub fn surface_format(&self) -> wgpu::TextureFormat {
+        self.config.format
+    }
+
+    /// Physical size the surface (or offscreen frame) is configured for
+    pub fn surface_size(&self) -> (u32, u32) {
+        (self.config.width, self.config.height)
+    }
+
+    /// Multisample count that paint callback pipelines must match
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// Depth format that paint callback pipelines must declare (`None` = no depth buffer)
+    pub fn depth_format(&self) -> Option<wgpu::TextureFormat> {
+        self.depth_format
+    }
+
+    /// Anisotropic filtering level of the texture samplers, after clamping to what the adapter supports (1 = off)
+    pub fn anisotropy(&self) -> u16 {
+        self.textures.borrow().anisotropy
+    }
+
+    /// Whether the device was lost, in which case [`WgpuRenderer::recover_device`] must be called
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Relaxed)
+    }
+
+    /// Recreates the adapter, device and every GPU resource after the device was lost,
+    /// re-uploading the textures egui has sent so far.
+    ///
+    /// Renderers made with [`WgpuRenderer::create_shared`] keep the lost device and must be recreated.
+    pub async fn recover_device(&mut self) -> Result<(), RendererError> {
+        let Some(instance) = self.instance.clone() else { return Err(RendererError::ExternalDevice) };
+        let adapter = request_adapter(&instance, self.renderer_config.power_preference, self.surface.as_ref()).await?;
+        let renderer_config = RendererConfig {
+            // changed since the renderer was created
+            background: self.background,
+            dithering: self.dithering,
+            max_frame_latency: self.config.desired_maximum_frame_latency,
+            required_features: self.device.features(),
+            ..self.renderer_config.clone()
+        };
+        let backups = std::mem::take(&mut self.textures.borrow_mut().backups);
+        let retained = std::mem::take(&mut self.retained);
+        let clear_color = self.clear_color;
+
+        *self = Self::create_internal(instance, adapter, self.surface.take(), self.config.clone(), &renderer_config).await?;
+        self.clear_color = clear_color;
+
+        let images = backups.into_iter().collect::<Vec<_>>();
+        self.upload_textures(&images);
+        for (id, layer) in retained {
+            self.retain_meshes(id, layer.triangles);
+        }
+        log::info!("GPU device recovered ({} textures re-uploaded)", images.len());
+        Ok(())
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    /// Takes effect from the next rendered frame
+    pub fn set_dithering(&mut self, dithering: bool) {
+        self.dithering = dithering;
+    }
+
+    pub fn dithering(&self) -> bool {
+        self.dithering
+    }
+
+    /// See [`RendererConfig::max_frame_latency`]; reconfigures the surface right away
+    pub fn set_max_frame_latency(&mut self, max_frame_latency: u32) {
+        self.config.desired_maximum_frame_latency = max_frame_latency.max(1);
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+    }
+
+    /// Confines the own UI to a rectangle of the frame (x, y, width, height in physical pixels), e.g. a tool panel over a game's scene.
+    /// `None` draws over the whole frame again.
+    ///
+    /// The frame is no longer cleared: the background is drawn inside the rectangle only, and the rest keeps what
+    /// the target contains (e.g. the scene drawn into the view given to [`WgpuRenderer::render_to_view`]).
+    /// Like [`Background::Keep`], that only holds without MSAA and supersampling. The screen size of the UI becomes the rectangle's,
+    /// while [`Pane`] origins stay relative to the frame.
+    pub fn set_target_rect(&mut self, rect: Option<(u32, u32, u32, u32)>) {
+        self.target_rect = rect;
+    }
+
+    pub fn target_rect(&self) -> Option<(u32, u32, u32, u32)> {
+        self.target_rect
+    }
+
+    /// Color cleared under [`Background::Shader`] (e.g. egui's `window_fill` to follow the theme)
+    pub fn set_clear_color(&mut self, color: egui::Color32) {
+        // clear values are written as is, so an sRGB (or float) target takes linear values
+        let [r, g, b, a] = match is_linear_format(self.config.format) {
+            true => egui::Rgba::from(color).to_array(),
+            false => color.to_normalized_gamma_f32(),
+        };
+        self.clear_color = wgpu::Color { r: r as f64, g: g as f64, b: b as f64, a: a as f64 };
+    }
+
+    /// Reconfigures the surface (and size-dependent targets) for a new physical size
     pub fn request_resize(&mut self, screen: &ScreenDescriptor) {
         self.config.width = screen.screen_width;
         self.config.height = screen.screen_height;
-        self.surface.configure(&self.device, &self.config);
-        buffer::send_uniform_buffer(&self.queue, &screen, &self.uniform_buffer);
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        self.recreate_size_dependent();
+        buffer::send_uniform_buffer(&self.queue, screen, self.dithering, self.predictable_texture_filtering, &self.uniform_buffer);
+    }
+
+    /// The only place where targets tied to the frame size are (re)allocated
+    fn recreate_size_dependent(&mut self) {
+        // free the VRAM now instead of whenever the last handle goes away
+        if let Some(msaa_texture) = self.msaa_texture.take() {
+            msaa_texture.destroy();
+        }
+        if let Some(supersampled_texture) = self.supersampled_texture.take() {
+            supersampled_texture.destroy();
+        }
+        if let Some(depth_texture) = self.depth_texture.take() {
+            depth_texture.destroy();
+        }
+
+        let size = self.scaled_size(frame_size(&self.config));
+        self.supersampled_texture = self.shared.downsampler.is_some().then(|| texture::into_supersampled_texture(&self.device, self.config.format, size));
+        self.msaa_texture = texture::into_msaa_texture(&self.device, self.config.format, size, self.sample_count);
+        self.depth_texture = self.depth_format.map(|format| texture::into_depth_texture(&self.device, format, size, self.sample_count));
     }
 
+    /// Size egui is drawn at for a frame of `size`, within the texture size limit of the device
+    fn scaled_size(&self, size: wgpu::Extent3d) -> wgpu::Extent3d {
+        if self.render_scale == 1.0 { return size };
+
+        let max = self.device.limits().max_texture_dimension_2d;
+        let scale = |v: u32| ((v as f32 * self.render_scale).round() as u32).clamp(1, max);
+        wgpu::Extent3d { width: scale(size.width), height: scale(size.height), depth_or_array_layers: 1 }
+    }
+
+    /// Updates the uniforms for a new pixels-per-point without touching the surface
     pub fn request_rescale(&mut self, screen: &ScreenDescriptor) {
-        buffer::send_uniform_buffer(&self.queue, &screen, &self.uniform_buffer);
+        buffer::send_uniform_buffer(&self.queue, screen, self.dithering, self.predictable_texture_filtering, &self.uniform_buffer);
     }
 
     pub fn render(
         &mut self,
         screen: &ScreenDescriptor,
         triangles: &[egui::ClippedPrimitive],
-        images: &egui::TexturesDelta) -> Result<(), wgpu::SurfaceError>
+        images: &egui::TexturesDelta) -> Result<FrameStats, wgpu::SurfaceError>
+    {
+        self.render_with_panes(screen, triangles, images, &mut [])
+    }
+
+    /// Like [`WgpuRenderer::render`], with the panes drawn after (over) this renderer's own UI,
+    /// each clipped to its own rectangle.
+    pub fn render_with_panes(
+        &mut self,
+        screen: &ScreenDescriptor,
+        triangles: &[egui::ClippedPrimitive],
+        images: &egui::TexturesDelta,
+        panes: &mut [Pane]) -> Result<FrameStats, wgpu::SurfaceError>
+    {
+        let Some(surface) = &self.surface else { return Err(wgpu::SurfaceError::Other) };
+        let texture = surface.get_current_texture()?;
+        debug_assert_eq!(texture.texture.size(), frame_size(&self.config), "surface texture does not match the configured size");
+        debug_assert!(
+            self.msaa_texture.as_ref().is_none_or(|t| t.size() == self.scaled_size(frame_size(&self.config))),
+            "MSAA texture does not match the configured size"
+        );
+        let texture_view = texture.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let stats = self.render_internal(&texture_view, screen, triangles, images, panes);
+        if self.low_latency && let Err(err) = self.device.poll(wgpu::PollType::wait_indefinitely()) {
+            log::warn!("Unable to wait for the frame (reason: {err})");
+        }
+        if let Some(callback) = self.screenshot.take() {
+            self.take_screenshot(&texture.texture, callback);
+        }
+        texture.present();
+        self.poll_screenshots();
+        Ok(stats)
+    }
+
+    /// Hands the pixels of the next frame presented by [`WgpuRenderer::render`] to `callback`, e.g. to attach to a bug report.
+    /// The frame is copied without waiting for the GPU; the callback is called by a later `render` or
+    /// [`WgpuRenderer::poll_screenshots`] once the copy has finished.
+    /// It is dropped without a call when the surface cannot be copied from or has an unsupported format.
+    pub fn request_screenshot(&mut self, callback: impl FnOnce(egui::ColorImage) + 'static) {
+        self.screenshot = Some(Box::new(callback));
+    }
+
+    /// Calls the callbacks of the screenshots whose copy has finished.
+    /// Returns whether some are still in flight, in which case it has to be called again later
+    /// (e.g. when the event loop would otherwise wait for input).
+    pub fn poll_screenshots(&mut self) -> bool {
+        if self.pending_screenshots.is_empty() { return false };
+
+        let _ = self.device.poll(wgpu::PollType::Poll);
+        let mut i = 0;
+        while i < self.pending_screenshots.len() {
+            let Some(result) = self.pending_screenshots[i].0.try_finish() else {
+                i += 1;
+                continue;
+            };
+            let (readback, callback) = self.pending_screenshots.swap_remove(i);
+            match result {
+                Ok(pixels) => callback(egui::ColorImage::from_rgba_unmultiplied([readback.width() as usize, readback.height() as usize], &pixels)),
+                Err(err) => log::warn!("Screenshot skipped (reason: {err})"),
+            }
+        }
+        !self.pending_screenshots.is_empty()
+    }
+
+    fn take_screenshot(&mut self, texture: &wgpu::Texture, callback: ScreenshotFn) {
+        // the copy is submitted before the frame is presented, and read once the GPU is done with it
+        match texture::Readback::start(&self.device, &self.queue, texture) {
+            Ok(readback) => self.pending_screenshots.push((readback, callback)),
+            Err(err) => log::warn!("Screenshot skipped (reason: {err})"),
+        }
+    }
+
+    /// Renders into an owned texture (surface format, `COPY_SRC`) instead of the surface.
+    /// The pixels can be read back with [`WgpuRenderer::read_texture`].
+    pub fn render_to_texture(
+        &mut self,
+        screen: &ScreenDescriptor,
+        triangles: &[egui::ClippedPrimitive],
+        images: &egui::TexturesDelta) -> wgpu::Texture
+    {
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen texture"),
+            size: wgpu::Extent3d { width: screen.screen_width, height: screen.screen_height, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        self.render_internal(&texture.create_view(&wgpu::TextureViewDescriptor::default()), screen, triangles, images, &mut []);
+        texture
+    }
+
+    /// Renders into a view supplied by the caller, e.g. the current frame of an engine that owns the surface.
+    /// The view must cover the whole texture (of the format given to [`WgpuRenderer::from_existing`]) and
+    /// allow `RENDER_ATTACHMENT`. The commands are submitted before returning.
+    pub fn render_to_view(
+        &mut self,
+        texture_view: &wgpu::TextureView,
+        screen: &ScreenDescriptor,
+        triangles: &[egui::ClippedPrimitive],
+        images: &egui::TexturesDelta) -> FrameStats
+    {
+        self.render_internal(texture_view, screen, triangles, images, &mut [])
+    }
+
+    /// Reads back a `COPY_SRC` texture as tightly packed RGBA8 rows (blocks until the copy is done).
+    /// Supports 8-bit RGBA and BGRA formats, and `Rgba16Float` (see [`RendererConfig::hdr`]), which is clipped to the SDR range
+    /// and sRGB-encoded; other formats fail with [`ReadbackError::UnsupportedFormat`].
+    pub fn read_texture(&self, texture: &wgpu::Texture) -> Result<Vec<u8>, ReadbackError> {
+        texture::read_texture_image(&self.device, &self.queue, texture)
+    }
+
+    fn render_internal(
+        &mut self,
+        texture_view: &wgpu::TextureView,
+        screen: &ScreenDescriptor,
+        triangles: &[egui::ClippedPrimitive],
+        images: &egui::TexturesDelta,
+        panes: &mut [Pane]) -> FrameStats
     {
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render encoder"),
         });
 
-        let texture = self.surface.get_current_texture()?;
-        let texture_view = texture.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let texture = texture_view.texture();
 
-        encode_bg(&mut encoder, &texture_view, &self.bg_pipeline);
+        // with supersampling, egui is drawn into a larger texture and downsampled into the frame afterwards
+        let size = self.scaled_size(texture.size());
+        let supersampled_texture = match &self.supersampled_texture {
+            Some(supersampled_texture) if supersampled_texture.size() == size => Some(supersampled_texture.clone()),
+            Some(_) => Some(texture::into_supersampled_texture(&self.device, texture.format(), size)),
+            None => None,
+        };
+        let supersampled_view = supersampled_texture.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+        let frame_view = supersampled_view.as_ref().unwrap_or(texture_view);
+        // descriptors and pane origins are scaled along so that scissor rects and viewports cover the same part of the frame
+        let scale = size.width as f32 / texture.width() as f32;
 
-        texture::update_samplers(&self.device, images.set.iter().map(|(_, image)| image.options), &mut self.samplers);
-        texture::send_texture_images_pos(&self.queue, &images.set, &self.texture_cache);
-        let resources = texture::send_texture_images_new(&self.device, &self.queue, &self.samplers, &images.set);
-        texture::update_bind_groups(&self.device, &self.texture_layout, resources, &mut self.texture_cache);
+        // with MSAA, draw into the multisampled texture and resolve into the frame
+        let msaa_texture = match &self.msaa_texture {
+            Some(msaa_texture) if msaa_texture.size() == size => Some(msaa_texture.clone()),
+            Some(_) => texture::into_msaa_texture(&self.device, texture.format(), size, self.sample_count),
+            None => None,
+        };
+        let msaa_view = msaa_texture.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+        let (target_view, resolve_view) = match &msaa_view {
+            Some(msaa_view) => (msaa_view, Some(frame_view)),
+            None => (frame_view, None),
+        };
+        // the background and foreground pipelines were both built with `self.sample_count`
+        debug_assert_eq!(target_view.texture().sample_count(), self.sample_count, "color attachment does not match the pipelines' sample count");
 
-        let (vbuffer_size, ibuffer_size) = buffer::measure_buffer_size(triangles);
-        if (vbuffer_size > 0) && (ibuffer_size > 0) {
-            buffer::send_vertex_buffer(&mut self.device, &self.queue, vbuffer_size, triangles, &mut self.vertex_buffer);
-            buffer::send_index_buffer(&mut self.device, &self.queue, ibuffer_size, triangles, &mut self.index_buffer);
-            encode_fg(
-                &mut encoder, &texture_view, &self.fg_pipeline,
-                &self.vertex_buffer, &self.index_buffer,
-                &self.uniform, &self.texture_fallback,
-                &self.texture_cache,
-                screen,
-                triangles,
+        let depth_texture = match &self.depth_texture {
+            Some(depth_texture) if depth_texture.size() == size => Some(depth_texture.clone()),
+            Some(depth_texture) => Some(texture::into_depth_texture(&self.device, depth_texture.format(), size, self.sample_count)),
+            None => None,
+        };
+        let depth_view = depth_texture.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        // the own UI covers the target rect, or else the frame
+        let (origin, region) = match self.target_rect {
+            Some(rect) => scale_rect(rect, scale, size),
+            None => ([0, 0], size),
+        };
+        let screen = ScreenDescriptor {
+            pixel_per_point: screen.pixel_per_point * scale,
+            screen_width: region.width,
+            screen_height: region.height,
+        };
+        let layer = self.prepare_layer(screen, triangles, images);
+        let pane_layers = panes.iter_mut()
+            .map(|pane| {
+                // drawn into the same frame, so dithered (or not) like it
+                pane.renderer.dithering = self.dithering;
+                pane.renderer.prepare_layer(scale_screen(&pane.screen, scale), pane.triangles, pane.images)
+            })
+            .collect::<Vec<_>>()
+        ;
+
+        let (fill_color, bg_pipeline) = match self.background {
+            Background::Shader => (Some(self.clear_color), Some(&self.shared.bg_pipeline)),
+            Background::SolidColor(color) => (Some(color), None),
+            Background::None => (Some(wgpu::Color::TRANSPARENT), None),
+            Background::Keep => (None, None),
+        };
+        // with a target rect, only the rect is filled (in the pass) and the rest of the frame is kept
+        let load = match fill_color {
+            Some(color) if self.target_rect.is_none() => wgpu::LoadOp::Clear(color),
+            _ => wgpu::LoadOp::Load,
+        };
+        let has_region = (region.width > 0) && (region.height > 0);
+
+        // a frame cannot be measured while the previous result is still being read back
+        let timed = self.timer.as_mut().is_some_and(|timer| timer.poll(&self.device));
+
+        let mut stats = layer.stats;
+        {
+            // always begun since it clears the frame
+            let mut pass = begin_frame_pass(
+                &mut encoder, target_view, resolve_view, depth_view.as_ref(), load,
+                self.timer.as_ref().filter(|_| timed).map(timer::GpuTimer::timestamp_writes),
             );
+            if has_region {
+                let [x, y] = origin;
+                pass.set_viewport(x as f32, y as f32, region.width as f32, region.height as f32, 0.0, 1.0);
+                pass.set_scissor_rect(x, y, region.width, region.height);
+                if self.target_rect.is_some() && let Some(color) = fill_color {
+                    pass.set_blend_constant(color);
+                    pass.set_pipeline(&self.shared.fill_pipeline);
+                    pass.draw(0..3, 0..1);
+                }
+                if let Some(bg_pipeline) = bg_pipeline {
+                    pass.set_pipeline(bg_pipeline);
+                    pass.draw(0..3, 0..1);
+                }
+                stats.draw_calls = self.draw_layer(&mut pass, &layer, origin, size);
+            }
+            for (pane, pane_layer) in panes.iter().zip(&pane_layers) {
+                let origin = pane.origin.map(|v| (v as f32 * scale).round() as u32);
+                stats.add(&pane_layer.stats);
+                stats.draw_calls += pane.renderer.draw_layer(&mut pass, pane_layer, origin, size);
+            }
+            // the GL backend resolves MSAA with the last scissor rect still applied
+            pass.set_scissor_rect(0, 0, size.width, size.height);
+        }
+        if timed && let Some(timer) = &self.timer {
+            timer.resolve(&mut encoder);
+        }
+        if let (Some(downsampler), Some(supersampled_view)) = (&self.shared.downsampler, &supersampled_view) {
+            downsampler.blit(&self.device, &mut encoder, supersampled_view, texture_view);
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
-        texture.present();
 
-        buffer::release_textures(&images.free, &mut self.texture_cache);
-        Ok(())
+        if timed && let Some(timer) = &mut self.timer {
+            timer.start_readback();
+        }
+        stats.gpu_time_ms = self.timer.as_ref().and_then(timer::GpuTimer::gpu_time_ms);
+
+        self.release_textures(&images.free);
+        for pane in panes.iter() {
+            pane.renderer.release_textures(&pane.images.free);
+        }
+
+        stats
+    }
+
+    /// Uploads the uniforms, textures and meshes of one egui context ahead of the render pass
+    fn prepare_layer<'a>(&mut self, screen: ScreenDescriptor, triangles: &'a [egui::ClippedPrimitive], images: &egui::TexturesDelta) -> PreparedLayer<'a> {
+        // keep screen size in points up to date even if no rescale was requested
+        buffer::send_uniform_buffer(&self.queue, &screen, self.dithering, self.predictable_texture_filtering, &self.uniform_buffer);
+
+        let textures_allocated = self.upload_textures(&images.set);
+
+        let mut stats = FrameStats {
+            textures_uploaded: images.set.len() as u32,
+            textures_allocated,
+            ..Default::default()
+        };
+
+        let (vbuffer_size, ibuffer_size) = buffer::measure_buffer_size(triangles);
+        let commands = buffer::batch_draw_commands(triangles);
+        stats.textures_allocated += self.restore_textures(&commands);
+        let index_start = ((vbuffer_size > 0) && (ibuffer_size > 0)).then(|| {
+            buffer::send_mesh_buffer(&self.device, &self.queue, (vbuffer_size, ibuffer_size), &commands, &mut self.mesh_buffer);
+            stats.vertices = (vbuffer_size / size_of::<Vertex>() as u64) as u32;
+            stats.indices = (ibuffer_size / size_of::<u32>() as u64) as u32;
+            vbuffer_size
+        });
+
+        PreparedLayer { screen, commands, index_start, stats }
     }
+
+    /// Returns the number of draw calls
+    fn draw_layer(&self, pass: &mut wgpu::RenderPass, layer: &PreparedLayer, origin: [u32; 2], frame_size: wgpu::Extent3d) -> u32 {
+        let textures = self.textures.borrow();
+        draw_commands(
+            &self.device, &self.queue,
+            pass, &self.shared.fg_pipeline,
+            &self.mesh_buffer, layer.index_start,
+            &self.uniform, &self.shared.texture_fallback,
+            &textures.cache,
+            &layer.screen,
+            origin,
+            frame_size,
+            self.depth_format,
+            &self.retained,
+            &layer.commands,
+        )
+    }
+
+    fn release_textures(&self, ids: &[egui::TextureId]) {
+        let mut textures = self.textures.borrow_mut();
+        let texture::TextureStore { cache: texture_cache, backups, .. } = &mut *textures;
+        texture::release_textures(ids, texture_cache, backups);
+    }
+
+    /// Frees the GPU memory of every texture (and every sampler but the default one), e.g. after closing a view full of images.
+    ///
+    /// The CPU copies kept for device loss stay, so the textures egui still draws (the font atlas at least)
+    /// are uploaded again by the next frame that uses them, without egui having to send them again.
+    pub fn clear_textures(&mut self) {
+        let mut textures = self.textures.borrow_mut();
+        textures.cache.clear();
+        textures.samplers.retain(|options, _| *options == egui::TextureOptions::LINEAR);
+        textures.cleared = true;
+    }
+
+    /// Uploads meshes that stay on the GPU until replaced or released, e.g. a static panel that then is no longer
+    /// tessellated and uploaded every frame. They are drawn wherever [`RetainedMeshes::paint_callback`] with the same id
+    /// appears among the primitives of a frame, with that frame's screen descriptor.
+    pub fn retain_meshes(&mut self, id: egui::Id, triangles: Vec<egui::ClippedPrimitive>) {
+        let layer = retained::RetainedLayer::upload(&self.device, &self.queue, triangles);
+        self.retained.insert(id, layer);
+    }
+
+    pub fn release_meshes(&mut self, id: egui::Id) {
+        self.retained.remove(&id);
+    }
+
+    /// Re-uploads the textures dropped by [`WgpuRenderer::clear_textures`] that this frame draws.
+    /// Returns the number of textures that had to be created
+    fn restore_textures(&self, commands: &[buffer::DrawCommand]) -> u32 {
+        if !self.textures.borrow().cleared { return 0 };
+
+        let images = {
+            let textures = self.textures.borrow();
+            let mut images = Vec::new();
+            let mut restore = |id: egui::TextureId| {
+                if !textures.cache.contains_key(&id) && !images.iter().any(|(restored, _)| *restored == id) && let Some(image) = textures.backups.get(&id) {
+                    images.push((id, image.clone()));
+                }
+            };
+            for command in commands {
+                match command {
+                    buffer::DrawCommand::Mesh { texture_id, .. } => restore(*texture_id),
+                    buffer::DrawCommand::Callback { .. } => {}
+                    buffer::DrawCommand::Retained { id } => {
+                        for primitive in self.retained.get(id).into_iter().flat_map(|layer| &layer.triangles) {
+                            if let egui::epaint::Primitive::Mesh(mesh) = &primitive.primitive {
+                                restore(mesh.texture_id);
+                            }
+                        }
+                    }
+                }
+            }
+            images
+        };
+        let uploaded = match images.is_empty() {
+            true => 0,
+            false => {
+                log::debug!("restore {} cleared textures", images.len());
+                self.upload_textures(&images)
+            }
+        };
+
+        // other frames (e.g. of a shared renderer) may still draw the rest
+        let mut textures = self.textures.borrow_mut();
+        textures.cleared = textures.backups.keys().any(|id| !textures.cache.contains_key(id));
+        uploaded
+    }
+
+    /// Uploads and frees textures ahead of a frame, e.g. the font atlas before the first one
+    pub fn update_textures(&mut self, images: &egui::TexturesDelta) {
+        self.upload_textures(&images.set);
+        self.release_textures(&images.free);
+    }
+
+    /// Returns the number of textures that had to be created
+    fn upload_textures(&self, images: &[(egui::TextureId, egui::epaint::ImageDelta)]) -> u32 {
+        let mut textures = self.textures.borrow_mut();
+        let texture::TextureStore { samplers, cache: texture_cache, backups, staging, anisotropy, .. } = &mut *textures;
+        texture::update_samplers(&self.device, images.iter().map(|(_, image)| image.options), *anisotropy, samplers);
+        texture::send_texture_images_pos(&self.device, &self.queue, &self.shared.mipmaps, &self.shared.texture_layout, samplers, images, texture_cache, staging);
+        let resources = texture::send_texture_images_new(&self.device, &self.queue, &self.shared.mipmaps, samplers, images, texture_cache, staging);
+        let allocated = resources.len() as u32;
+        texture::update_bind_groups(&self.device, &self.shared.texture_layout, resources, texture_cache);
+        texture::backup_images(images, backups);
+        allocated
+    }
+}
+
+/// Falls back to a software adapter (e.g. llvmpipe or WARP) when no hardware one is usable,
+/// so that the app still starts (slowly) on CI machines or with a broken driver
+async fn request_adapter(instance: &wgpu::Instance, power_preference: wgpu::PowerPreference, compatible_surface: Option<&wgpu::Surface<'_>>) -> Result<wgpu::Adapter, wgpu::RequestAdapterError> {
+    let options = wgpu::RequestAdapterOptions { power_preference, force_fallback_adapter: false, compatible_surface };
+    match instance.request_adapter(&options).await {
+        Ok(adapter) => Ok(adapter),
+        Err(err) => {
+            log::warn!("no hardware adapter found, use fallback adapter (reason: {err})");
+            instance.request_adapter(&wgpu::RequestAdapterOptions { force_fallback_adapter: true, ..options }).await
+        }
+    }
+}
+
+fn make_instance(renderer_config: &RendererConfig) -> wgpu::Instance {
+    wgpu::Instance::new(&wgpu::InstanceDescriptor {
+        backends: renderer_config.backends,
+        flags: renderer_config.instance_flags,
+        ..Default::default()
+    })
+}
+
+/// Size of a mesh buffer with room for (vertices, indices)
+fn mesh_buffer_size((vertices, indices): (usize, usize)) -> u64 {
+    // a buffer may not be empty
+    ((size_of::<Vertex>() * vertices + size_of::<u32>() * indices) as u64).max(wgpu::COPY_BUFFER_ALIGNMENT)
 }
 
-fn make_background_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::RenderPipeline {
+/// Textures of an egui context, starting with the sampler the fallback texture is bound with
+fn make_texture_store(device: &wgpu::Device, anisotropy: u16) -> Rc<RefCell<texture::TextureStore>> {
+    let linear_sampler = texture::into_sampler(device, egui::TextureOptions::LINEAR, anisotropy, Some("Texture sampler fallback"));
+    Rc::new(RefCell::new(texture::TextureStore {
+        samplers: [(egui::TextureOptions::LINEAR, linear_sampler)].into_iter().collect(),
+        cache: egui::ahash::HashMap::default(),
+        backups: egui::ahash::HashMap::default(),
+        staging: staging::StagingPool::new(),
+        anisotropy,
+        cleared: false,
+    }))
+}
+
+fn make_uniform(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Uniform buffer"),
+        contents: bytemuck::cast_slice(&[
+            buffer::UniformBuffer {
+                screen_size: [0.0, 0.0],
+                dithering: 0,
+                predictable_texture_filtering: 0,
+            }
+        ]),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+    let uniform = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Uniform bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &uniform_buffer,
+                    offset: 0,
+                    size: None,
+                }),
+            }
+        ],
+    });
+    (uniform_buffer, uniform)
+}
+
+fn frame_size(config: &wgpu::SurfaceConfiguration) -> wgpu::Extent3d {
+    wgpu::Extent3d { width: config.width, height: config.height, depth_or_array_layers: 1 }
+}
+
+/// Shared by every pipeline drawn into the frame so that they agree on the sample count of the color attachment
+/// (and none of them ends up with a sample mask of 0 = no samples written)
+fn multisample_state(sample_count: u32) -> wgpu::MultisampleState {
+    wgpu::MultisampleState { count: sample_count, mask: !0, alpha_to_coverage_enabled: false }
+}
+
+/// egui neither tests nor writes the depth buffer, it is there for paint callbacks only
+fn depth_stencil_state(depth_format: Option<wgpu::TextureFormat>) -> Option<wgpu::DepthStencilState> {
+    depth_format.map(|format| wgpu::DepthStencilState {
+        format,
+        depth_write_enabled: false,
+        depth_compare: wgpu::CompareFunction::Always,
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    })
+}
+
+/// `fill`: the pipeline filling the target rect with the blend constant instead of the background shader
+fn make_background_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, sample_count: u32, depth_format: Option<wgpu::TextureFormat>, fill: bool) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(wgpu::include_wgsl!("bg_shader.wgsl"));
+    let (vs_entry, fs_entry, blend) = match fill {
+        true => {
+            // src (1.0) * constant + dst * 0
+            let constant = wgpu::BlendComponent { src_factor: wgpu::BlendFactor::Constant, dst_factor: wgpu::BlendFactor::Zero, operation: wgpu::BlendOperation::Add };
+            ("vs_fill", "fs_fill", wgpu::BlendState { color: constant, alpha: constant })
+        }
+        false => ("vs_main", "fs_main", wgpu::BlendState::REPLACE),
+    };
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Render background pipline layout"),
         bind_group_layouts: &[],
@@ -244,11 +1464,11 @@ fn make_background_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfigu
     });
 
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor{
-        label: Some("Render background pipline"),
+        label: Some(if fill { "Render fill pipeline" } else { "Render background pipline" }),
         layout: Some(&pipeline_layout),
         vertex: wgpu::VertexState {
             module: &shader,
-            entry_point: Some("vs_main"),
+            entry_point: Some(vs_entry),
             buffers: &[],
             compilation_options: wgpu::PipelineCompilationOptions::default()
         },
@@ -261,15 +1481,15 @@ fn make_background_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfigu
             polygon_mode: wgpu::PolygonMode::Fill,
             conservative: false,
         },
-        depth_stencil: None,
-        multisample: wgpu::MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
+        depth_stencil: depth_stencil_state(depth_format),
+        multisample: multisample_state(sample_count),
         fragment: Some(wgpu::FragmentState {
             module:&shader,
-            entry_point: Some("fs_main"),
+            entry_point: Some(fs_entry),
             targets: &[
                 Some(wgpu::ColorTargetState {
                     format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
+                    blend: Some(blend),
                     write_mask: wgpu::ColorWrites::ALL,
                 })
             ],
@@ -280,7 +1500,52 @@ fn make_background_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfigu
     })
 }
 
-fn make_freground_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, bindgroups: &[&wgpu::BindGroupLayout]) -> wgpu::RenderPipeline {
+/// `WGPU_TRACE=<dir>` records a replayable API trace into `dir`.
+/// Needs the `trace` feature, and slows every wgpu call down, so keep it off in release.
+fn device_trace() -> wgpu::Trace {
+    match std::env::var_os("WGPU_TRACE") {
+        #[cfg(feature = "trace")]
+        Some(dir) => {
+            log::info!("Recording a wgpu trace into {}", std::path::Path::new(&dir).display());
+            wgpu::Trace::Directory(dir.into())
+        }
+        #[cfg(not(feature = "trace"))]
+        Some(_) => {
+            log::warn!("WGPU_TRACE is ignored, build with the `trace` feature to record a trace");
+            wgpu::Trace::Off
+        }
+        None => wgpu::Trace::Off,
+    }
+}
+
+/// Targets that hold linear values: sRGB ones (the hardware encodes on write) and float ones (e.g. HDR surfaces)
+fn is_linear_format(format: wgpu::TextureFormat) -> bool {
+    format.is_srgb() || matches!(format, wgpu::TextureFormat::Rgba16Float | wgpu::TextureFormat::Rgba32Float)
+}
+
+/// egui colors are gamma encoded, so a linear target needs them decoded first
+fn fragment_entry_point(format: wgpu::TextureFormat) -> &'static str {
+    match is_linear_format(format) {
+        true => "fs_main_linear_framebuffer",
+        false => "fs_main_gamma_framebuffer",
+    }
+}
+
+/// egui tessellates with premultiplied alpha (same blending as egui-wgpu)
+const PREMULTIPLIED_ALPHA_BLEND: wgpu::BlendState = wgpu::BlendState {
+    color: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+        operation: wgpu::BlendOperation::Add,
+    },
+    alpha: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::OneMinusDstAlpha,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    },
+};
+
+fn make_freground_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, sample_count: u32, depth_format: Option<wgpu::TextureFormat>, bindgroups: &[&wgpu::BindGroupLayout]) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(wgpu::include_wgsl!("egui.wgsl"));
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Render widget pipline layout"),
@@ -312,27 +1577,16 @@ fn make_freground_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfigur
             polygon_mode: wgpu::PolygonMode::Fill,
             conservative: false,
         },
-        depth_stencil: None,
-        multisample: wgpu::MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
+        depth_stencil: depth_stencil_state(depth_format),
+        multisample: multisample_state(sample_count),
         fragment: Some(wgpu::FragmentState {
             module: &shader,
-            entry_point: Some("fs_main_gamma_framebuffer"),
+            entry_point: Some(fragment_entry_point(config.format)),
             compilation_options: wgpu::PipelineCompilationOptions::default(),
             targets: &[
                 Some(wgpu::ColorTargetState {
                     format: config.format,
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent {
-                            src_factor: wgpu::BlendFactor::One,
-                            dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                            operation: wgpu::BlendOperation::Add,
-                        },
-                        alpha: wgpu::BlendComponent {
-                            src_factor: wgpu::BlendFactor::OneMinusDstAlpha,
-                            dst_factor: wgpu::BlendFactor::One,
-                            operation: wgpu::BlendOperation::Add,
-                        },
-                    }),
+                    blend: Some(PREMULTIPLIED_ALPHA_BLEND),
                     write_mask: wgpu::ColorWrites::ALL,
                 })
             ],
@@ -342,109 +1596,265 @@ fn make_freground_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfigur
     })
 }
 
-fn encode_bg(encoder: &mut wgpu::CommandEncoder, texture_view: &wgpu::TextureView, pipeline: &wgpu::RenderPipeline) {
-    let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-        label: Some("Render background pass"),
+/// Begins the single pass of a frame, which `load` clears (the background is drawn into it afterwards)
+fn begin_frame_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    texture_view: &wgpu::TextureView,
+    resolve_view: Option<&wgpu::TextureView>,
+    depth_view: Option<&wgpu::TextureView>,
+    load: wgpu::LoadOp<wgpu::Color>,
+    timestamp_writes: Option<wgpu::RenderPassTimestampWrites>) -> wgpu::RenderPass<'a>
+{
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Render pass"),
         color_attachments: &[
             Some(wgpu::RenderPassColorAttachment {
-                view: &texture_view,
+                view: texture_view,
                 depth_slice: None,
-                resolve_target: None,
+                resolve_target: resolve_view,
                 ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color{ r: 0.1, g: 0.2, b: 0.3, a: 1.0 }),
+                    load,
                     store: wgpu::StoreOp::Store,
                 },
-            })
+            }),
         ],
-        depth_stencil_attachment: None,
-        timestamp_writes: None,
+        depth_stencil_attachment: depth_view.map(|view| wgpu::RenderPassDepthStencilAttachment {
+            view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                // only needed within the pass
+                store: wgpu::StoreOp::Discard,
+            }),
+            stencil_ops: None,
+        }),
+        timestamp_writes,
         occlusion_query_set: None,
         multiview_mask: None,
-    });
-
-    pass.set_pipeline(pipeline);
-    pass.draw(0..3, 0..1);
+    })
 }
 
-fn encode_fg(
-    encoder: &mut wgpu::CommandEncoder,
-    texture_view: &wgpu::TextureView,
+#[allow(clippy::too_many_arguments)]
+/// Draws the meshes and paint callbacks of one egui context whose screen starts at `origin` (physical pixels)
+fn draw_commands(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    pass: &mut wgpu::RenderPass,
     pipeline: &wgpu::RenderPipeline,
-    vertex_buffer: &wgpu::Buffer,
-    index_buffer: &wgpu::Buffer,
+    mesh_buffer: &wgpu::Buffer,
+    index_start: Option<u64>,
     uniform_bind_group: &wgpu::BindGroup,
     bind_group_fallback: &wgpu::BindGroup,
     texture_cache: &egui::ahash::HashMap<egui::TextureId, texture::TextureResource>,
     screen: &ScreenDescriptor,
-    triangles: &[egui::ClippedPrimitive])
+    origin: [u32; 2],
+    frame_size: wgpu::Extent3d,
+    depth_format: Option<wgpu::TextureFormat>,
+    retained: &egui::IdMap<retained::RetainedLayer>,
+    commands: &[buffer::DrawCommand]) -> u32
 {
-    let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-        label: Some("Render mesh pass"),
-        color_attachments: &[
-            Some(wgpu::RenderPassColorAttachment {
-                view: texture_view,
-                depth_slice: None,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Load,
-                    store: wgpu::StoreOp::Store,
-                },
-            }),
-        ],
-        depth_stencil_attachment: None,
-        timestamp_writes: None,
-        occlusion_query_set: None,
-        multiview_mask: None,
-    });
+    let [origin_x, origin_y] = origin;
+    // the part of the frame this screen may draw into
+    let bounds = wgpu::Extent3d {
+        width: screen.screen_width.min(frame_size.width.saturating_sub(origin_x)),
+        height: screen.screen_height.min(frame_size.height.saturating_sub(origin_y)),
+        depth_or_array_layers: 1,
+    };
+    let scissor_rect = |clip_rect: &egui::Rect| to_scissor_rect(clip_rect, screen.pixel_per_point, bounds)
+        .map(|(x, y, width, height)| (x + origin_x, y + origin_y, width, height))
+    ;
 
-    pass.set_viewport(0.0, 0.0, screen.screen_width as f32, screen.screen_height as f32, 0.0, 1.0);
+    pass.set_viewport(origin_x as f32, origin_y as f32, screen.screen_width as f32, screen.screen_height as f32, 0.0, 1.0);
     pass.set_pipeline(pipeline);
 
-    // reset viewport
     pass.set_bind_group(0, uniform_bind_group, &[]);
 
-    let mut voffset = 0;
-    let mut ioffset = 0;
+    // all meshes are packed into one buffer (vertices, then indices), so bind it once
+    // (nothing to bind for a frame without meshes)
+    if let Some(index_start) = index_start {
+        pass.set_vertex_buffer(0, mesh_buffer.slice(..index_start));
+        pass.set_index_buffer(mesh_buffer.slice(index_start..), wgpu::IndexFormat::Uint32);
+    }
+
+    let mut base_vertex = 0;
+    let mut index_offset = 0;
+    let mut draw_calls = 0;
 
-    for egui::ClippedPrimitive{ clip_rect, primitive } in triangles {
-        let Some((x, y, width, height)) = to_scissor_rect(clip_rect, &screen) else { continue };
-        pass.set_scissor_rect(x, y, width, height);
+    for command in commands {
+        match command {
+            buffer::DrawCommand::Mesh{ clip_rect, texture_id: id, meshes } => {
+                let (vertex_count, index_count) = meshes.iter()
+                    .fold((0, 0), |(vcount, icount), mesh| (vcount + mesh.vertices.len() as u32, icount + mesh.indices.len() as u32))
+                ;
+                let vertex_start = base_vertex;
+                let irange = index_offset..index_offset + index_count;
 
-        match primitive {
-            egui::epaint::Primitive::Mesh(egui::Mesh{ indices, vertices, texture_id: id }) => {
-                let vrange = voffset..voffset + (vertices.len() * size_of::<Vertex>()) as u64;
-                let irange = ioffset..ioffset + (indices.len() * size_of::<u32>()) as u64;
+                base_vertex += vertex_count;
+                index_offset = irange.end;
+                if irange.is_empty() { continue };
 
-                voffset = vrange.end;
-                ioffset = irange.end;
+                let Some((x, y, width, height)) = scissor_rect(clip_rect) else { continue };
+                pass.set_scissor_rect(x, y, width, height);
 
+                // e.g. TextureId::User that was never uploaded
                 let bind_group = texture_cache.get(id).map(|res| &res.bind_group).unwrap_or_else(|| {
-                    log::warn!("bind group is not found, use fallback bind group");
+                    log::warn!("bind group is not found (id: {id:?}), use fallback bind group");
                     bind_group_fallback
                 });
                 pass.set_bind_group(1, bind_group, &[]);
 
-                pass.set_vertex_buffer(0, vertex_buffer.slice(vrange));
-                pass.set_index_buffer(index_buffer.slice(irange), wgpu::IndexFormat::Uint32);
-                pass.draw_indexed(0..indices.len() as u32, 0, 0..1);
+                pass.draw_indexed(irange, vertex_start as i32, 0..1);
+                draw_calls += 1;
+            }
+            buffer::DrawCommand::Callback{ clip_rect, callback: paint_callback } => {
+                let Some((x, y, width, height)) = scissor_rect(clip_rect) else { continue };
+                pass.set_scissor_rect(x, y, width, height);
+
+                let Some(callback) = paint_callback.callback.downcast_ref::<Callback>() else {
+                    log::warn!("unknown paint callback type, skipped");
+                    continue;
+                };
+                let info = CallbackInfo {
+                    clip_rect_px: (x, y, width, height),
+                    screen: *screen,
+                    origin_px: origin,
+                    depth_format,
+                };
+                callback.paint(device, queue, pass, &info);
+
+                // the callback may have replaced viewport, scissor rect, pipeline, bind groups and buffers
+                pass.set_viewport(origin_x as f32, origin_y as f32, screen.screen_width as f32, screen.screen_height as f32, 0.0, 1.0);
+                pass.set_scissor_rect(x, y, width, height);
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, uniform_bind_group, &[]);
+                if let Some(index_start) = index_start {
+                    pass.set_vertex_buffer(0, mesh_buffer.slice(..index_start));
+                    pass.set_index_buffer(mesh_buffer.slice(index_start..), wgpu::IndexFormat::Uint32);
+                }
             }
-            egui::epaint::Primitive::Callback(_paint_callback) => {
-                panic!("Not implemented");
+            buffer::DrawCommand::Retained{ id } => {
+                let Some(layer) = retained.get(id) else {
+                    log::warn!("retained meshes are not found (id: {id:?}), skipped");
+                    continue;
+                };
+                // retained meshes never draw other retained meshes
+                draw_calls += draw_commands(
+                    device, queue,
+                    pass, pipeline,
+                    &layer.mesh_buffer, layer.index_start,
+                    uniform_bind_group, bind_group_fallback,
+                    texture_cache,
+                    screen,
+                    origin,
+                    frame_size,
+                    depth_format,
+                    &egui::IdMap::default(),
+                    &buffer::batch_draw_commands(&layer.triangles),
+                );
+
+                // back to the buffer of the frame
+                if let Some(index_start) = index_start {
+                    pass.set_vertex_buffer(0, mesh_buffer.slice(..index_start));
+                    pass.set_index_buffer(mesh_buffer.slice(index_start..), wgpu::IndexFormat::Uint32);
+                }
             }
         }
     }
+
+    draw_calls
+}
+
+/// Scales a pane's descriptor to the supersampled frame
+fn scale_screen(screen: &ScreenDescriptor, scale: f32) -> ScreenDescriptor {
+    ScreenDescriptor {
+        pixel_per_point: screen.pixel_per_point * scale,
+        screen_width: (screen.screen_width as f32 * scale).round() as u32,
+        screen_height: (screen.screen_height as f32 * scale).round() as u32,
+    }
+}
+
+/// Target rect (physical pixels) scaled along with the frame and clamped to it, as an origin and a size
+fn scale_rect((x, y, width, height): (u32, u32, u32, u32), scale: f32, frame_size: wgpu::Extent3d) -> ([u32; 2], wgpu::Extent3d) {
+    let x = ((x as f32 * scale).round() as u32).min(frame_size.width);
+    let y = ((y as f32 * scale).round() as u32).min(frame_size.height);
+    let size = wgpu::Extent3d {
+        width: ((width as f32 * scale).round() as u32).min(frame_size.width - x),
+        height: ((height as f32 * scale).round() as u32).min(frame_size.height - y),
+        depth_or_array_layers: 1,
+    };
+    ([x, y], size)
 }
 
-fn to_scissor_rect(clip_rect: &egui::Rect, &ScreenDescriptor{ pixel_per_point: ppp, screen_width, screen_height }: &ScreenDescriptor) -> Option<(u32, u32, u32, u32)> {
-    let x0 = (clip_rect.left() * ppp).round() as u32;
-    let y0 = (clip_rect.top() * ppp).round() as u32;
-    let x1 = (clip_rect.right() * ppp).round() as u32;
-    let y1 = (clip_rect.bottom() * ppp).round() as u32;
+/// Clamped to the frame actually rendered into, which wgpu validates scissor rects against
+/// (the screen descriptor may lag behind a resize)
+fn to_scissor_rect(clip_rect: &egui::Rect, ppp: f32, frame_size: wgpu::Extent3d) -> Option<(u32, u32, u32, u32)> {
+    let wgpu::Extent3d { width: frame_width, height: frame_height, .. } = frame_size;
+
+    // clamp after rounding so that x + w <= width and y + h <= height always hold
+    let x0 = ((clip_rect.left() * ppp).round() as u32).min(frame_width);
+    let y0 = ((clip_rect.top() * ppp).round() as u32).min(frame_height);
+    let x1 = ((clip_rect.right() * ppp).round() as u32).clamp(x0, frame_width);
+    let y1 = ((clip_rect.bottom() * ppp).round() as u32).clamp(y0, frame_height);
+
+    let (w, h) = (x1 - x0, y1 - y0);
+    ((w != 0) && (h != 0)).then_some((x0, y0, w, h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32) -> wgpu::Extent3d {
+        wgpu::Extent3d { width, height, depth_or_array_layers: 1 }
+    }
+
+    fn rect(x0: f32, y0: f32, x1: f32, y1: f32) -> egui::Rect {
+        egui::Rect::from_min_max(egui::pos2(x0, y0), egui::pos2(x1, y1))
+    }
+
+    #[test]
+    fn scissor_rect_at_fractional_pixels_per_point() {
+        // 10.2..30.6 and 5.6..20.4 physical pixels
+        assert_eq!(to_scissor_rect(&rect(8.16, 4.48, 24.48, 16.32), 1.25, frame(100, 100)), Some((10, 6, 21, 14)));
+        // 15..45 and 7.5..22.5 physical pixels (halves round away from zero)
+        assert_eq!(to_scissor_rect(&rect(10.0, 5.0, 30.0, 15.0), 1.5, frame(100, 100)), Some((15, 8, 30, 15)));
+    }
+
+    #[test]
+    fn scissor_rect_is_clamped_to_the_right_and_bottom_edges() {
+        assert_eq!(to_scissor_rect(&rect(50.0, 60.0, 500.0, 400.0), 1.0, frame(100, 80)), Some((50, 60, 50, 20)));
+        assert_eq!(to_scissor_rect(&egui::Rect::EVERYTHING, 1.5, frame(100, 80)), Some((0, 0, 100, 80)));
+    }
+
+    #[test]
+    fn empty_scissor_rect_is_none() {
+        // past the frame
+        assert_eq!(to_scissor_rect(&rect(120.0, 10.0, 150.0, 20.0), 1.0, frame(100, 80)), None);
+        // thinner than half a pixel
+        assert_eq!(to_scissor_rect(&rect(10.0, 10.0, 10.2, 20.0), 1.0, frame(100, 80)), None);
+        assert_eq!(to_scissor_rect(&rect(10.0, 10.0, 20.0, 10.0), 1.25, frame(100, 80)), None);
+    }
 
-    let x = x0.clamp(0, screen_width);
-    let y = y0.clamp(0, screen_height);
-    let w = u32::saturating_sub(x1.clamp(0, screen_width), x);
-    let h = u32::saturating_sub(y1.clamp(0, screen_height), y);
+    #[test]
+    fn scissor_rect_stays_inside_the_frame() {
+        let size = frame(97, 61);
+        for ppp in [1.0, 1.25, 1.5, 1.75, 2.0, 2.5] {
+            for step in 0..40 {
+                let min = step as f32 * 1.7;
+                let clip_rect = rect(min, min * 0.6, min + 33.3, min * 0.6 + 21.9);
+                if let Some((x, y, w, h)) = to_scissor_rect(&clip_rect, ppp, size) {
+                    assert!((x + w <= size.width) && (y + h <= size.height), "{clip_rect:?} at {ppp}: {:?}", (x, y, w, h));
+                }
+            }
+        }
+    }
 
-    ((w != 0) && (h != 0)).then(|| (x, y, w, h))
+    #[test]
+    fn multisample_state_writes_every_sample() {
+        for count in [1, 4] {
+            let state = multisample_state(count);
+            assert_eq!(state.count, count);
+            assert_eq!(state.mask, !0);
+            assert!(!state.alpha_to_coverage_enabled);
+        }
+    }
 }
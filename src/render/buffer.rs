@@ -1,23 +1,11 @@
 use std::num::NonZero;
 
-use egui::ahash::HashMap;
-
-use crate::render::texture::TextureResource;
-
-pub fn make_index_buffer(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+/// Holds the vertices followed by the indices of a frame
+pub fn make_mesh_buffer(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
     device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Foreground index buffer"),
+        label: Some("Foreground mesh buffer"),
         size,
-        usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    })
-}
-
-pub fn make_vertex_buffer(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
-    device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Foreground vertex buffer"),
-        size,
-        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
         mapped_at_creation: false,
     })
 }
@@ -36,48 +24,86 @@ pub fn measure_buffer_size(triangles: &[egui::ClippedPrimitive]) -> (u64, u64) {
     ((vertex_count * size_of::<egui::epaint::Vertex>()) as u64, (index_count * size_of::<u32>()) as u64)
 }
 
-pub fn send_vertex_buffer(device: &mut wgpu::Device, queue: &wgpu::Queue, buffer_size: u64, triangles: &[egui::ClippedPrimitive], buffer: &mut wgpu::Buffer) {
-    if buffer.size() <= buffer_size {
-        *buffer = make_vertex_buffer(device, buffer_size * 2);
-    }
-    let Some(mut view) = queue.write_buffer_with(buffer, 0, NonZero::<u64>::new(buffer.size()).unwrap())
-        else { unreachable!("Unexpected vertex buffer error") }
-    ;
-    let mut offset = 0;
-    for egui::ClippedPrimitive{ primitive, .. } in triangles.iter() {
+/// Run of primitives that can be issued with a single draw call
+pub enum DrawCommand<'a> {
+    /// consecutive meshes sharing the same clip rect and texture
+    Mesh { clip_rect: egui::Rect, texture_id: egui::TextureId, meshes: Vec<&'a egui::Mesh> },
+    Callback { clip_rect: egui::Rect, callback: &'a egui::PaintCallback },
+    /// meshes kept by `WgpuRenderer::retain_meshes`
+    Retained { id: egui::Id },
+}
+
+pub fn batch_draw_commands(triangles: &[egui::ClippedPrimitive]) -> Vec<DrawCommand<'_>> {
+    let mut commands = Vec::<DrawCommand>::with_capacity(triangles.len());
+
+    for egui::ClippedPrimitive{ clip_rect, primitive } in triangles {
         match primitive {
-            egui::epaint::Primitive::Mesh(egui::Mesh{ vertices, .. }) => {
-                let start = offset;
-                let end = offset + vertices.len() * size_of::<egui::epaint::Vertex>();
-                offset = end;
-                view[start..end].copy_from_slice(bytemuck::cast_slice(vertices));
+            egui::epaint::Primitive::Mesh(mesh) => {
+                if let Some(DrawCommand::Mesh{ clip_rect: last_clip_rect, texture_id, meshes }) = commands.last_mut()
+                    && (last_clip_rect == clip_rect) && (*texture_id == mesh.texture_id)
+                {
+                    meshes.push(mesh);
+                    continue;
+                }
+                commands.push(DrawCommand::Mesh{ clip_rect: *clip_rect, texture_id: mesh.texture_id, meshes: vec![mesh] });
+            }
+            egui::epaint::Primitive::Callback(callback) => {
+                if let Some(super::RetainedMeshes(id)) = callback.callback.downcast_ref() {
+                    commands.push(DrawCommand::Retained{ id: *id });
+                    continue;
+                }
+                commands.push(DrawCommand::Callback{ clip_rect: *clip_rect, callback });
             }
-            egui::epaint::Primitive::Callback(_) => {}
         }
     }
+
+    commands
+}
+
+/// Size of the buffer replacing one of `capacity` bytes that cannot hold `needed` bytes (`None` while they fit):
+/// twice the need, kept a multiple of `COPY_BUFFER_ALIGNMENT`
+fn grown_buffer_size(capacity: u64, needed: u64) -> Option<u64> {
+    (capacity < needed).then(|| wgpu::util::align_to(needed * 2, wgpu::COPY_BUFFER_ALIGNMENT))
 }
 
-pub fn send_index_buffer(device: &mut wgpu::Device, queue: &wgpu::Queue, buffer_size: u64, triangles: &[egui::ClippedPrimitive], buffer: &mut wgpu::Buffer) {
-    if buffer.size() <= buffer_size {
-        *buffer = make_index_buffer(device, buffer_size * 2);
+/// Writes the vertices and then the indices of all meshes through a single mapping.
+/// The buffer only grows (to twice the needed size) when the frame does not fit.
+pub fn send_mesh_buffer(device: &wgpu::Device, queue: &wgpu::Queue, (vertex_size, index_size): (u64, u64), commands: &[DrawCommand], buffer: &mut wgpu::Buffer) {
+    let buffer_size = vertex_size + index_size;
+    if let Some(size) = grown_buffer_size(buffer.size(), buffer_size) {
+        *buffer = make_mesh_buffer(device, size);
     }
-    let Some(mut view) = queue.write_buffer_with(buffer, 0, NonZero::<u64>::new(buffer.size() as u64).unwrap())
-        else { unreachable!("Unexpected index buffer error") }
+    let Some(mut view) = queue.write_buffer_with(buffer, 0, NonZero::<u64>::new(buffer_size).unwrap())
+        else { unreachable!("Unexpected mesh buffer error") }
     ;
+    let (vertex_view, index_view) = view.split_at_mut(vertex_size as usize);
 
-    let mut offset = 0;
-    for egui::ClippedPrimitive{ primitive, .. } in triangles.iter() {
-        match primitive {
-            egui::epaint::Primitive::Mesh(egui::Mesh{ indices, .. }) => {
-                let start = offset;
-                let end = offset + indices.len() * size_of::<u32>();
-                offset = end;
-                view[start..end].copy_from_slice(bytemuck::cast_slice(indices));
+    let mut voffset = 0;
+    let mut ioffset = 0;
+    for command in commands {
+        let DrawCommand::Mesh{ meshes, .. } = command else { continue };
+
+        // indices of a merged mesh are relative to the first vertex of its batch
+        let mut base = 0;
+        for egui::Mesh{ indices, vertices, .. } in meshes {
+            let vend = voffset + vertices.len() * size_of::<egui::epaint::Vertex>();
+            vertex_view[voffset..vend].copy_from_slice(bytemuck::cast_slice(vertices));
+            voffset = vend;
+
+            let iend = ioffset + indices.len() * size_of::<u32>();
+            if base == 0 {
+                index_view[ioffset..iend].copy_from_slice(bytemuck::cast_slice(indices));
+            }
+            else {
+                for (dest, index) in index_view[ioffset..iend].chunks_exact_mut(size_of::<u32>()).zip(indices) {
+                    dest.copy_from_slice(&(index + base).to_ne_bytes());
+                }
             }
-            egui::epaint::Primitive::Callback(_) => {}
+            ioffset = iend;
+
+            base += vertices.len() as u32;
         }
     }
-
 }
 
 
@@ -85,26 +111,70 @@ pub fn send_index_buffer(device: &mut wgpu::Device, queue: &wgpu::Queue, buffer_
 #[repr(C)]
 #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct UniformBuffer {
-    pub screen_size_optionts: [f32; 2],
+    pub screen_size: [f32; 2],
     pub dithering: u32,
-    pub predicatable_texture_fintering: u32,
+    pub predictable_texture_filtering: u32,
 }
 
-pub fn send_uniform_buffer(queue: &wgpu::Queue, screen: &super::ScreenDescriptor, buffer: &wgpu::Buffer) {
+pub fn send_uniform_buffer(queue: &wgpu::Queue, screen: &super::ScreenDescriptor, dithering: bool, predictable_texture_filtering: bool, buffer: &wgpu::Buffer) {
     let w = screen.screen_width as f32 / screen.pixel_per_point;
     let h = screen.screen_height as f32 / screen.pixel_per_point;
 
     let content = UniformBuffer {
-        screen_size_optionts: [w, h],
-        dithering: 0,
-        predicatable_texture_fintering: 0,
+        screen_size: [w, h],
+        dithering: dithering as u32,
+        predictable_texture_filtering: predictable_texture_filtering as u32,
     };
 
     queue.write_buffer(buffer, 0, bytemuck::cast_slice(&[content]));
 }
 
-pub fn release_textures(ids: &[egui::TextureId], cache: &mut HashMap<egui::TextureId, TextureResource>) {
-    for id in ids {
-        cache.remove(id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(vertex_count: usize, index_count: usize) -> egui::ClippedPrimitive {
+        let mesh = egui::Mesh {
+            indices: vec![0; index_count],
+            vertices: vec![egui::epaint::Vertex::default(); vertex_count],
+            ..Default::default()
+        };
+        egui::ClippedPrimitive { clip_rect: egui::Rect::EVERYTHING, primitive: egui::epaint::Primitive::Mesh(mesh) }
+    }
+
+    #[test]
+    fn buffer_size_adds_up_the_meshes() {
+        let callback = egui::ClippedPrimitive {
+            clip_rect: egui::Rect::EVERYTHING,
+            primitive: egui::epaint::Primitive::Callback(egui::PaintCallback { rect: egui::Rect::EVERYTHING, callback: std::sync::Arc::new(()) }),
+        };
+        let vertex_size = size_of::<egui::epaint::Vertex>() as u64;
+        assert_eq!(measure_buffer_size(&[mesh(4, 6), callback, mesh(3, 3)]), (7 * vertex_size, 9 * 4));
+        assert_eq!(measure_buffer_size(&[]), (0, 0));
+    }
+
+    #[test]
+    fn buffer_exactly_at_capacity_is_kept() {
+        let (vertex_size, index_size) = measure_buffer_size(&[mesh(4, 6)]);
+        let capacity = vertex_size + index_size;
+        assert_eq!(grown_buffer_size(capacity, capacity), None);
+        assert_eq!(grown_buffer_size(capacity, capacity - 4), None);
+    }
+
+    #[test]
+    fn buffer_one_byte_short_doubles() {
+        let capacity = 64;
+        assert_eq!(grown_buffer_size(capacity, capacity + 1), Some(132));
+        assert_eq!(grown_buffer_size(capacity, capacity + 4), Some(136));
+    }
+
+    #[test]
+    fn grown_buffer_is_aligned() {
+        // a size that is not a multiple of COPY_BUFFER_ALIGNMENT, e.g. from 16-bit indices
+        for needed in [1, 2, 3, 5, 6, 7, 13, 42] {
+            let size = grown_buffer_size(0, needed).unwrap();
+            assert!(size >= needed * 2, "{size} for {needed}");
+            assert_eq!(size % wgpu::COPY_BUFFER_ALIGNMENT, 0, "{size} for {needed}");
+        }
     }
 }
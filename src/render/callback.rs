@@ -0,0 +1,58 @@
+use super::ScreenDescriptor;
+
+/// Where a paint callback is drawn, for callbacks that set their own viewport
+#[derive(Clone, Copy, Debug)]
+pub struct CallbackInfo {
+    /// Clip rect in physical pixels of the frame (x, y, width, height), the same rect the scissor is set to
+    pub clip_rect_px: (u32, u32, u32, u32),
+    /// Screen the callback belongs to, in the pixels of the frame (scaled along with supersampling)
+    pub screen: ScreenDescriptor,
+    /// Top-left corner of `screen` in the frame (non-zero for split panes)
+    pub origin_px: [u32; 2],
+    /// Format of the depth buffer attached to the pass, which callback pipelines must declare
+    /// (`None` without `RendererConfig::depth_format`)
+    pub depth_format: Option<wgpu::TextureFormat>,
+}
+impl CallbackInfo {
+    /// Viewport (x, y, width, height) that maps `rect` (in points, e.g. `egui::PaintCallback::rect`) onto the frame
+    pub fn viewport_px(&self, rect: egui::Rect) -> (f32, f32, f32, f32) {
+        let ppp = self.screen.pixel_per_point;
+        (
+            self.origin_px[0] as f32 + rect.left() * ppp,
+            self.origin_px[1] as f32 + rect.top() * ppp,
+            rect.width() * ppp,
+            rect.height() * ppp,
+        )
+    }
+}
+
+/// User drawing hook for `egui::PaintCallback`.
+///
+/// The scissor rect is already set to `info.clip_rect_px` when `paint` is called.
+/// The renderer restores its viewport, scissor rect, pipeline, bind groups and buffers afterwards.
+pub trait CallbackTrait: Send + Sync {
+    fn paint(&self, device: &wgpu::Device, queue: &wgpu::Queue, pass: &mut wgpu::RenderPass<'_>, info: &CallbackInfo);
+}
+impl<F> CallbackTrait for F
+where
+    F: Fn(&wgpu::Device, &wgpu::Queue, &mut wgpu::RenderPass<'_>, &CallbackInfo) + Send + Sync,
+{
+    fn paint(&self, device: &wgpu::Device, queue: &wgpu::Queue, pass: &mut wgpu::RenderPass<'_>, info: &CallbackInfo) {
+        self(device, queue, pass, info)
+    }
+}
+
+/// Payload of `egui::PaintCallback::callback` that this renderer knows how to run.
+pub struct Callback(Box<dyn CallbackTrait>);
+impl Callback {
+    pub fn new_paint_callback(rect: egui::Rect, callback: impl CallbackTrait + 'static) -> egui::PaintCallback {
+        egui::PaintCallback {
+            rect,
+            callback: std::sync::Arc::new(Callback(Box::new(callback))),
+        }
+    }
+
+    pub fn paint(&self, device: &wgpu::Device, queue: &wgpu::Queue, pass: &mut wgpu::RenderPass<'_>, info: &CallbackInfo) {
+        self.0.paint(device, queue, pass, info);
+    }
+}
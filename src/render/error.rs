@@ -0,0 +1,97 @@
+/// Failures of creating or recovering a [`super::WgpuRenderer`]
+#[derive(Debug)]
+pub enum RendererError {
+    /// No adapter matches the requested backends (e.g. no GPU and no software fallback)
+    AdapterNotFound(wgpu::RequestAdapterError),
+    DeviceRequest(wgpu::RequestDeviceError),
+    Surface(wgpu::CreateSurfaceError),
+    RawHandle(wgpu::rwh::HandleError),
+    /// The surface of a shared renderer cannot use the format of the renderer it shares the device with
+    UnsupportedSurfaceFormat(wgpu::TextureFormat),
+    /// wgpu does not allow a zero-sized surface (e.g. a window that is minimized or not yet configured)
+    ZeroSize,
+    /// The renderer was made by `from_existing`, so it has no instance or adapter to create from
+    ExternalDevice,
+}
+
+impl std::fmt::Display for RendererError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AdapterNotFound(err) => write!(f, "no adapter found ({err})"),
+            Self::DeviceRequest(err) => write!(f, "failed to request a device ({err})"),
+            Self::Surface(err) => write!(f, "failed to create a surface ({err})"),
+            Self::RawHandle(err) => write!(f, "failed to get a window handle ({err})"),
+            Self::UnsupportedSurfaceFormat(format) => write!(f, "surface format {format:?} is not supported by the window"),
+            Self::ZeroSize => write!(f, "the frame size must not be 0"),
+            Self::ExternalDevice => write!(f, "the device is owned by the caller"),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::AdapterNotFound(err) => Some(err),
+            Self::DeviceRequest(err) => Some(err),
+            Self::Surface(err) => Some(err),
+            Self::RawHandle(err) => Some(err),
+            Self::UnsupportedSurfaceFormat(_) | Self::ZeroSize | Self::ExternalDevice => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestAdapterError> for RendererError {
+    fn from(value: wgpu::RequestAdapterError) -> Self {
+        Self::AdapterNotFound(value)
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for RendererError {
+    fn from(value: wgpu::RequestDeviceError) -> Self {
+        Self::DeviceRequest(value)
+    }
+}
+
+impl From<wgpu::CreateSurfaceError> for RendererError {
+    fn from(value: wgpu::CreateSurfaceError) -> Self {
+        Self::Surface(value)
+    }
+}
+
+impl From<wgpu::rwh::HandleError> for RendererError {
+    fn from(value: wgpu::rwh::HandleError) -> Self {
+        Self::RawHandle(value)
+    }
+}
+
+/// Failures of reading a texture back (see [`super::WgpuRenderer::read_texture`])
+#[derive(Debug)]
+pub enum ReadbackError {
+    /// Only formats that convert to RGBA8 can be read back
+    UnsupportedFormat(wgpu::TextureFormat),
+    /// The texture was not created with `COPY_SRC`
+    NotCopyable,
+    Map(wgpu::BufferAsyncError),
+    Poll(wgpu::PollError),
+}
+
+impl std::fmt::Display for ReadbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedFormat(format) => write!(f, "texture format {format:?} cannot be read back"),
+            Self::NotCopyable => write!(f, "the texture does not allow COPY_SRC"),
+            Self::Map(err) => write!(f, "failed to map the readback buffer ({err})"),
+            Self::Poll(err) => write!(f, "failed to wait for the readback ({err})"),
+        }
+    }
+}
+
+impl std::error::Error for ReadbackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Map(err) => Some(err),
+            Self::Poll(err) => Some(err),
+            Self::UnsupportedFormat(_) | Self::NotCopyable => None,
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use super::buffer;
+
+/// Stands in for meshes kept on the GPU by [`super::WgpuRenderer::retain_meshes`]:
+/// a paint callback carrying it draws the meshes retained under the same id where it appears in the paint order
+pub struct RetainedMeshes(pub egui::Id);
+impl RetainedMeshes {
+    pub fn paint_callback(id: egui::Id) -> egui::PaintCallback {
+        egui::PaintCallback { rect: egui::Rect::EVERYTHING, callback: Arc::new(Self(id)) }
+    }
+}
+
+/// Meshes of one region, uploaded once into a buffer of their own
+pub struct RetainedLayer {
+    pub triangles: Vec<egui::ClippedPrimitive>,
+    pub mesh_buffer: wgpu::Buffer,
+    /// `None` without meshes
+    pub index_start: Option<u64>,
+}
+impl RetainedLayer {
+    pub fn upload(device: &wgpu::Device, queue: &wgpu::Queue, triangles: Vec<egui::ClippedPrimitive>) -> Self {
+        let (vbuffer_size, ibuffer_size) = buffer::measure_buffer_size(&triangles);
+        let mut mesh_buffer = buffer::make_mesh_buffer(device, (vbuffer_size + ibuffer_size).max(wgpu::COPY_BUFFER_ALIGNMENT));
+        let index_start = ((vbuffer_size > 0) && (ibuffer_size > 0)).then(|| {
+            let commands = buffer::batch_draw_commands(&triangles);
+            buffer::send_mesh_buffer(device, queue, (vbuffer_size, ibuffer_size), &commands, &mut mesh_buffer);
+            vbuffer_size
+        });
+
+        Self { triangles, mesh_buffer, index_start }
+    }
+}
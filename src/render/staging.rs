@@ -0,0 +1,113 @@
+use std::sync::mpsc;
+
+/// Reusable `COPY_SRC` buffers for large texture uploads (e.g. a video frame every frame),
+/// instead of the staging memory `Queue::write_texture` allocates for each of them.
+///
+/// A buffer is filled while mapped, copied into the texture in a submission of its own
+/// and mapped again in the background; it is reused once that mapping finishes.
+pub struct StagingPool {
+    /// mapped and ready to be filled
+    free: Vec<wgpu::Buffer>,
+    /// receives the buffers whose mapping finished
+    recycled: mpsc::Receiver<wgpu::Buffer>,
+    sender: mpsc::Sender<wgpu::Buffer>,
+}
+impl StagingPool {
+    /// Smaller uploads (e.g. new glyphs) go through `Queue::write_texture`
+    pub const MIN_UPLOAD_BYTES: usize = 256 * 1024;
+    /// Mapped buffers kept beyond this are released
+    const MAX_FREE_BUFFERS: usize = 4;
+
+    pub fn new() -> Self {
+        let (sender, recycled) = mpsc::channel();
+        Self { free: Vec::new(), recycled, sender }
+    }
+
+    /// Uploads `data` (tightly packed rows) into `texture` at `origin`
+    pub fn write_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        data: &[u8],
+        origin: wgpu::Origin3d,
+        size: wgpu::Extent3d)
+    {
+        let bytes_per_pixel = texture.format().block_copy_size(None).expect("Texture format must be copyable");
+        let row_bytes = (bytes_per_pixel * size.width) as usize;
+        // copies from a buffer need rows aligned to 256 bytes
+        let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize);
+        let upload_size = (padded_row_bytes * size.height as usize) as u64;
+
+        let buffer = self.acquire(device, upload_size);
+        {
+            let mut view = buffer.get_mapped_range_mut(..upload_size);
+            for (src, dest) in data.chunks_exact(row_bytes).zip(view.chunks_exact_mut(padded_row_bytes)) {
+                dest[..row_bytes].copy_from_slice(src);
+            }
+        }
+        buffer.unmap();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Texture upload encoder"),
+        });
+        encoder.copy_buffer_to_texture(
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes as u32),
+                    rows_per_image: Some(size.height),
+                },
+            },
+            wgpu::TexelCopyTextureInfo {
+                texture,
+                mip_level: 0,
+                origin,
+                aspect: wgpu::TextureAspect::All,
+            },
+            size,
+        );
+        // submitted right away so that it lands before anything reading the texture (e.g. mipmap generation)
+        queue.submit(std::iter::once(encoder.finish()));
+
+        // resolves once the copy has finished on the GPU
+        let sender = self.sender.clone();
+        let recycled = buffer.clone();
+        buffer.map_async(wgpu::MapMode::Write, .., move |result| {
+            if result.is_ok() {
+                let _ = sender.send(recycled);
+            }
+        });
+    }
+
+    /// Smallest mapped buffer of at least `size` bytes, or a new one
+    fn acquire(&mut self, device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+        let _ = device.poll(wgpu::PollType::Poll);
+        self.free.extend(self.recycled.try_iter());
+        if self.free.len() > Self::MAX_FREE_BUFFERS {
+            // keep the largest ones, they fit any upload the smaller ones do
+            self.free.sort_by_key(|buffer| std::cmp::Reverse(buffer.size()));
+            for buffer in self.free.drain(Self::MAX_FREE_BUFFERS..) {
+                buffer.destroy();
+            }
+        }
+
+        let fitting = self.free.iter().enumerate()
+            .filter(|(_, buffer)| buffer.size() >= size)
+            .min_by_key(|(_, buffer)| buffer.size())
+            .map(|(i, _)| i)
+        ;
+        if let Some(i) = fitting {
+            return self.free.swap_remove(i);
+        }
+
+        log::debug!("new staging buffer ({size} bytes)");
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texture staging buffer"),
+            size,
+            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: true,
+        })
+    }
+}
@@ -1,12 +1,21 @@
-use std::{borrow::Cow, collections::hash_map};
+use std::{borrow::Cow, collections::hash_map, sync::{Arc, mpsc}};
 use egui::ahash::HashMap;
+use wgpu::util::DeviceExt;
 
-pub fn into_sampler(device: &wgpu::Device, options: egui::TextureOptions, label: Option<&str>) -> wgpu::Sampler {
+use super::{error::ReadbackError, mipmap, staging};
+
+/// `anisotropy` (1..=16) only applies to linearly filtered textures, since wgpu requires every filter to be linear for it
+pub fn into_sampler(device: &wgpu::Device, options: egui::TextureOptions, anisotropy: u16, label: Option<&str>) -> wgpu::Sampler {
     let address_mode = match options.wrap_mode {
         egui::TextureWrapMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
         egui::TextureWrapMode::Repeat => wgpu::AddressMode::Repeat,
         egui::TextureWrapMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
     };
+    // a texture without mipmaps has a single level, so a linear mipmap filter changes nothing for it
+    let anisotropic = (anisotropy > 1)
+        && (options.magnification == egui::TextureFilter::Linear)
+        && (options.minification == egui::TextureFilter::Linear)
+        && (options.mipmap_mode != Some(egui::TextureFilter::Nearest));
     device.create_sampler(&wgpu::SamplerDescriptor {
         label,
         mag_filter: match options.magnification {
@@ -17,77 +26,238 @@ pub fn into_sampler(device: &wgpu::Device, options: egui::TextureOptions, label:
             egui::TextureFilter::Nearest => wgpu::FilterMode::Nearest,
             egui::TextureFilter::Linear => wgpu::FilterMode::Linear,
         },
+        mipmap_filter: match options.mipmap_mode {
+            _ if anisotropic => wgpu::MipmapFilterMode::Linear,
+            Some(egui::TextureFilter::Linear) => wgpu::MipmapFilterMode::Linear,
+            Some(egui::TextureFilter::Nearest) | None => wgpu::MipmapFilterMode::Nearest,
+        },
         address_mode_u: address_mode,
         address_mode_v: address_mode,
+        anisotropy_clamp: if anisotropic { anisotropy } else { 1 },
         ..Default::default()
     })
 }
 
-pub fn update_samplers(device: &wgpu::Device, texture_options: impl Iterator<Item = egui::TextureOptions>, samplers: &mut HashMap<egui::TextureOptions, wgpu::Sampler>) {
+/// Creates one sampler per distinct `TextureOptions`, so every texture is sampled with the filter it was uploaded with
+pub fn update_samplers(device: &wgpu::Device, texture_options: impl Iterator<Item = egui::TextureOptions>, anisotropy: u16, samplers: &mut HashMap<egui::TextureOptions, wgpu::Sampler>) {
     for options in texture_options {
         if let hash_map::Entry::Vacant(entry) = samplers.entry(options) {
-            entry.insert(into_sampler(device, options, None));
+            entry.insert(into_sampler(device, options, anisotropy, Some(&format!("sampler/options: {options:?}"))));
+        }
+    }
+}
+
+/// Reproduced as `TextureLocals` in egui.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TextureLocals {
+    /// maps egui's UVs (relative to the image) onto the part of the texture the image takes
+    pub uv_scale: [f32; 2],
+    pub _padding: [f32; 2],
+}
+impl TextureLocals {
+    fn new(texture_size: wgpu::Extent3d, image_size: wgpu::Extent3d) -> Self {
+        Self {
+            uv_scale: [image_size.width as f32 / texture_size.width as f32, image_size.height as f32 / texture_size.height as f32],
+            _padding: [0.0; 2],
         }
     }
 }
 
+pub fn into_texture_locals(device: &wgpu::Device, texture_size: wgpu::Extent3d, image_size: wgpu::Extent3d) -> wgpu::Buffer {
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Texture locals buffer"),
+        contents: bytemuck::cast_slice(&[TextureLocals::new(texture_size, image_size)]),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
 pub struct TextureResource {
+    /// may be larger than the image, see `headroom_size`
     pub texture: wgpu::Texture,
     pub bind_group: wgpu::BindGroup,
+    /// options the bind group's sampler was created from
+    pub options: egui::TextureOptions,
+    pub locals: wgpu::Buffer,
+    /// size of the image egui sent last
+    pub image_size: wgpu::Extent3d,
+}
+impl TextureResource {
+    /// Whether a full image can be written into this texture as is (into its top-left corner if the texture has headroom)
+    fn is_reusable_for(&self, id: egui::TextureId, size: wgpu::Extent3d, format: wgpu::TextureFormat, mip_level_count: u32, options: egui::TextureOptions) -> bool {
+        let texture_size = self.texture.size();
+        let fits = match can_have_headroom(id, mip_level_count, options) {
+            true => (texture_size.width >= size.width) && (texture_size.height >= size.height),
+            false => texture_size == size,
+        };
+        fits && (self.texture.format() == format) && (self.texture.mip_level_count() == mip_level_count) && (self.options == options)
+    }
+}
+
+/// Only the font atlas, which grows a few times in a row while new glyphs come in; other images may be large and change size freely.
+/// Mipmaps and repeating wrap modes need the image to fill the whole texture.
+fn can_have_headroom(id: egui::TextureId, mip_level_count: u32, options: egui::TextureOptions) -> bool {
+    (id == egui::TextureId::Managed(0)) && (mip_level_count == 1) && (options.wrap_mode == egui::TextureWrapMode::ClampToEdge)
+}
+
+/// Size of the texture for a font atlas that outgrew the previous one (e.g. doubling its height):
+/// room for the next two doublings, so that they are written into the same texture
+fn headroom_size(size: wgpu::Extent3d, previous: wgpu::Extent3d, max_texture_dimension: u32) -> wgpu::Extent3d {
+    const HEADROOM_FACTOR: u32 = 4;
+
+    let grow = |new: u32, old: u32| match new > old {
+        true => new.saturating_mul(HEADROOM_FACTOR).min(max_texture_dimension).max(new),
+        false => new,
+    };
+    wgpu::Extent3d {
+        width: grow(size.width, previous.width),
+        height: grow(size.height, previous.height),
+        depth_or_array_layers: 1,
+    }
+}
+
+/// Size of the texture for an image of `size` replacing one of `previous`
+fn allocation_size(id: egui::TextureId, size: wgpu::Extent3d, previous: Option<wgpu::Extent3d>, mip_level_count: u32, options: egui::TextureOptions, max_texture_dimension: u32) -> wgpu::Extent3d {
+    match previous {
+        Some(previous) if can_have_headroom(id, mip_level_count, options) && (previous != size) => headroom_size(size, previous, max_texture_dimension),
+        _ => size,
+    }
+}
+
+pub struct TextureStore {
+    pub samplers: HashMap<egui::TextureOptions, wgpu::Sampler>,
+    pub cache: HashMap<egui::TextureId, TextureResource>,
+    /// CPU copies of the whole images, re-uploaded when the device is lost
+    pub backups: HashMap<egui::TextureId, egui::epaint::ImageDelta>,
+    pub staging: staging::StagingPool,
+    /// anisotropic filtering level of the samplers (1 = off)
+    pub anisotropy: u16,
+    /// set by `WgpuRenderer::clear_textures` until every backed-up texture is uploaded again
+    pub cleared: bool,
 }
 
-pub fn send_texture_images_pos<'a>(
+#[allow(clippy::too_many_arguments)]
+pub fn send_texture_images_pos(
+    device: &wgpu::Device,
     queue: &wgpu::Queue,
+    mipmaps: &mipmap::MipmapGenerator,
+    layout: &wgpu::BindGroupLayout,
+    samplers: &HashMap<egui::TextureOptions, wgpu::Sampler>,
     images: &[(egui::TextureId, egui::epaint::ImageDelta)],
-    cache: &HashMap<egui::TextureId, TextureResource>)
+    cache: &mut HashMap<egui::TextureId, TextureResource>,
+    staging: &mut staging::StagingPool)
 {
     // send partially position
     for (id, img) in images.iter() {
-        let (Some(pos), Some(res)) = (img.pos, cache.get(id)) else { continue };
+        let (Some(pos), Some(res)) = (img.pos, cache.get_mut(id)) else { continue };
 
-        let data_bytes = match &img.image {
-            egui::ImageData::Color(data) => Cow::Borrowed(&data.pixels),
-        };
+        let (data_bytes, format) = image_bytes(&img.image);
+        if format != res.texture.format() {
+            log::warn!("texture format mismatch for partial update (id: {id:?}), skipped");
+            continue;
+        }
         let size = wgpu::Extent3d { width: img.image.width() as u32, height: img.image.height() as u32, depth_or_array_layers: 1 };
-        send_texture_image_internal(queue, &res.texture, bytemuck::cast_slice(&data_bytes), wgpu::Origin3d { x: pos[0] as u32, y: pos[1] as u32, z: 0 }, size);
+        send_texture_image_internal(device, queue, staging, &res.texture, &data_bytes, wgpu::Origin3d { x: pos[0] as u32, y: pos[1] as u32, z: 0 }, size);
+        mipmaps.generate(device, queue, &res.texture);
+
+        // the texture is unchanged, only a sampler switch needs a new bind group
+        if res.options != img.options {
+            let sampler = samplers.get(&img.options).expect("Sampler must be configured");
+            res.bind_group = into_bind_group(device, layout, &res.texture, sampler, &res.locals, Some(&format!("bind-group/id: {id:?}")));
+            res.options = img.options;
+        }
     }
 }
 
-pub fn into_texture(device: &wgpu::Device, size: wgpu::Extent3d, label: Option<&str>) -> wgpu::Texture {
+/// Pixel bytes of an egui image with the matching texture format
+fn image_bytes(image: &egui::ImageData) -> (Cow<'_, [u8]>, wgpu::TextureFormat) {
+    match image {
+        egui::ImageData::Color(data) => (Cow::Borrowed(bytemuck::cast_slice(&data.pixels)), wgpu::TextureFormat::Rgba8Unorm),
+    }
+}
+
+pub fn into_texture(device: &wgpu::Device, size: wgpu::Extent3d, format: wgpu::TextureFormat, mip_level_count: u32, label: Option<&str>) -> wgpu::Texture {
+    // mip levels are filled by rendering into them
+    let usage = match mip_level_count {
+        1 => wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        _ => wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::RENDER_ATTACHMENT,
+    };
     device.create_texture(&wgpu::TextureDescriptor {
         label,
         size,
-        mip_level_count: 1,
+        mip_level_count,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::Rgba8Unorm,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-        view_formats: &[wgpu::TextureFormat::Rgba8Unorm],
+        format,
+        usage,
+        view_formats: &[format],
     })
 }
 
+/// Uploads whole images.
+///
+/// An image re-sent with the same size, format and options is written into the cached texture,
+/// so its bind group stays valid. So is a grown image that fits into the headroom left by an earlier growth.
+/// Only the images that needed a new texture are returned.
 pub fn send_texture_images_new<'a>(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    mipmaps: &mipmap::MipmapGenerator,
     samplers: &'a HashMap<egui::TextureOptions, wgpu::Sampler>,
-    images: &[(egui::TextureId, egui::epaint::ImageDelta)]) -> impl Iterator<Item = (egui::TextureId, wgpu::Texture, &'a wgpu::Sampler)>
+    images: &[(egui::TextureId, egui::epaint::ImageDelta)],
+    cache: &mut HashMap<egui::TextureId, TextureResource>,
+    staging: &mut staging::StagingPool) -> Vec<(egui::TextureId, wgpu::Texture, &'a wgpu::Sampler, egui::TextureOptions, wgpu::Extent3d)>
 {
     images.iter()
         .filter_map(|(id, img)| {
             if img.pos.is_some() { return None };
 
-            // new texture
-            let data_bytes = match &img.image {
-                egui::ImageData::Color(data) => Cow::Borrowed(&data.pixels),
-            };
+            let (data_bytes, format) = image_bytes(&img.image);
             let size = wgpu::Extent3d { width: img.image.width() as u32, height: img.image.height() as u32, depth_or_array_layers: 1 };
-            let texture = into_texture(device, size, Some(&format!("texture/id: {id:?}")));
-            send_texture_image_internal(queue, &texture, bytemuck::cast_slice(&data_bytes), wgpu::Origin3d::ZERO, size);
-            Some((*id, texture, samplers.get(&img.options).expect("Sampler must be configured")))
+            // only textures asking for mipmaps get them (not the font atlas)
+            let mip_level_count = match img.options.mipmap_mode {
+                Some(_) => mipmap::mip_level_count(size),
+                None => 1,
+            };
+
+            // e.g. font atlas re-sent as a whole
+            if let Some(res) = cache.get_mut(id) && res.is_reusable_for(*id, size, format, mip_level_count, img.options) {
+                send_texture_image_internal(device, queue, staging, &res.texture, &data_bytes, wgpu::Origin3d::ZERO, size);
+                mipmaps.generate(device, queue, &res.texture);
+                if res.image_size != size {
+                    queue.write_buffer(&res.locals, 0, bytemuck::cast_slice(&[TextureLocals::new(res.texture.size(), size)]));
+                    res.image_size = size;
+                }
+                return None;
+            }
+
+            let previous = cache.get(id).map(|res| res.image_size);
+            let texture_size = allocation_size(*id, size, previous, mip_level_count, img.options, device.limits().max_texture_dimension_2d);
+
+            // new texture
+            let texture = into_texture(device, texture_size, format, mip_level_count, Some(&format!("texture/id: {id:?}")));
+            send_texture_image_internal(device, queue, staging, &texture, &data_bytes, wgpu::Origin3d::ZERO, size);
+            mipmaps.generate(device, queue, &texture);
+            Some((*id, texture, samplers.get(&img.options).expect("Sampler must be configured"), img.options, size))
         })
+        .collect()
 }
 
-fn send_texture_image_internal(queue: &wgpu::Queue, texture: &wgpu::Texture, data_bytes: &[u8], origin: wgpu::Origin3d, size: wgpu::Extent3d) {
+fn send_texture_image_internal(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    staging: &mut staging::StagingPool,
+    texture: &wgpu::Texture,
+    data_bytes: &[u8],
+    origin: wgpu::Origin3d,
+    size: wgpu::Extent3d)
+{
+    if data_bytes.len() >= staging::StagingPool::MIN_UPLOAD_BYTES {
+        staging.write_texture(device, queue, texture, data_bytes, origin, size);
+        return;
+    }
+
+    let bytes_per_pixel = texture.format().block_copy_size(None).expect("Texture format must be copyable");
     queue.write_texture(
         wgpu::TexelCopyTextureInfo {
             texture,
@@ -98,18 +268,198 @@ fn send_texture_image_internal(queue: &wgpu::Queue, texture: &wgpu::Texture, dat
         data_bytes,
         wgpu::TexelCopyBufferLayout {
             offset: 0,
-            bytes_per_row: Some(4 * size.width),
+            bytes_per_row: Some(bytes_per_pixel * size.width),
             rows_per_image: Some(size.height),
         },
         size
     );
 }
 
+pub fn into_msaa_texture(device: &wgpu::Device, format: wgpu::TextureFormat, size: wgpu::Extent3d, sample_count: u32) -> Option<wgpu::Texture> {
+    if sample_count <= 1 { return None };
+
+    Some(device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("MSAA texture"),
+        size,
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    }))
+}
+
+/// Depth buffer for paint callbacks, matching the color attachment in size and sample count
+pub fn into_depth_texture(device: &wgpu::Device, format: wgpu::TextureFormat, size: wgpu::Extent3d, sample_count: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Depth texture"),
+        size,
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    })
+}
+
+/// egui is drawn here at `render_scale` times the frame size, then downsampled into the frame
+pub fn into_supersampled_texture(device: &wgpu::Device, format: wgpu::TextureFormat, size: wgpu::Extent3d) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Supersampled texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+/// Reads back a `COPY_SRC` texture as tightly packed RGBA8 rows (blocks until the copy is done).
+/// 8-bit RGBA and BGRA formats are supported, and `Rgba16Float` (HDR surfaces), which is clipped to the SDR range.
+pub fn read_texture_image(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> Result<Vec<u8>, ReadbackError> {
+    let readback = Readback::start(device, queue, texture)?;
+    device.poll(wgpu::PollType::wait_indefinitely()).map_err(ReadbackError::Poll)?;
+    // the map callback has run once the wait returns
+    readback.try_finish().unwrap_or(Err(ReadbackError::Map(wgpu::BufferAsyncError)))
+}
+
+/// Copy of a texture on its way to the CPU
+pub struct Readback {
+    buffer: wgpu::Buffer,
+    format: wgpu::TextureFormat,
+    unpadded_bytes_per_row: u32,
+    padded_bytes_per_row: u32,
+    /// result of mapping the buffer, sent once the copy has finished on the GPU
+    mapped: mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>,
+}
+impl Readback {
+    /// Submits a copy of `texture` into a buffer, which is mapped in the background
+    pub fn start(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> Result<Self, ReadbackError> {
+        let format = texture.format();
+        if !matches!(format.remove_srgb_suffix(), wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Rgba16Float) {
+            return Err(ReadbackError::UnsupportedFormat(format));
+        }
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            return Err(ReadbackError::NotCopyable);
+        }
+
+        let width = texture.width();
+        let height = texture.height();
+        let bytes_per_pixel = format.block_copy_size(None).ok_or(ReadbackError::UnsupportedFormat(format))?;
+        // copy_texture_to_buffer requires 256-byte aligned rows
+        let unpadded_bytes_per_row = bytes_per_pixel * width;
+        let padded_bytes_per_row = unpadded_bytes_per_row.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback buffer"),
+            size: (padded_bytes_per_row * height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size()
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let (sender, mapped) = mpsc::channel();
+        buffer.map_async(wgpu::MapMode::Read, .., move |result| {
+            let _ = sender.send(result);
+        });
+
+        Ok(Self { buffer, format, unpadded_bytes_per_row, padded_bytes_per_row, mapped })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.unpadded_bytes_per_row / self.format.block_copy_size(None).unwrap_or(4)
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.buffer.size() / self.padded_bytes_per_row as u64) as u32
+    }
+
+    /// The pixels as RGBA8 rows, or `None` while the copy is in flight (the device has to be polled to get there)
+    pub fn try_finish(&self) -> Option<Result<Vec<u8>, ReadbackError>> {
+        match self.mapped.try_recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Some(Err(ReadbackError::Map(err))),
+            Err(mpsc::TryRecvError::Empty) => return None,
+            // the callback was dropped without a call
+            Err(mpsc::TryRecvError::Disconnected) => return Some(Err(ReadbackError::Map(wgpu::BufferAsyncError))),
+        }
+
+        let mut pixels = Vec::with_capacity(self.unpadded_bytes_per_row as usize * self.height() as usize);
+        {
+            let view = self.buffer.get_mapped_range(..);
+            for row in view.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..self.unpadded_bytes_per_row as usize]);
+            }
+        }
+        self.buffer.unmap();
+
+        match self.format.remove_srgb_suffix() {
+            wgpu::TextureFormat::Bgra8Unorm => {
+                for px in pixels.chunks_mut(4) {
+                    px.swap(0, 2);
+                }
+            }
+            wgpu::TextureFormat::Rgba16Float => pixels = rgba8_from_rgba16_float(&pixels),
+            _ => {}
+        }
+        Some(Ok(pixels))
+    }
+}
+
+/// Linear half floats to sRGB-encoded RGBA8 (like an `Rgba8UnormSrgb` target stores them), clipping values above 1.0
+fn rgba8_from_rgba16_float(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(8)
+        .flat_map(|px| {
+            let [r, g, b, a] = [0, 2, 4, 6].map(|i| f32_from_f16(u16::from_ne_bytes([px[i], px[i + 1]])));
+            [
+                egui::ecolor::gamma_u8_from_linear_f32(r.clamp(0.0, 1.0)),
+                egui::ecolor::gamma_u8_from_linear_f32(g.clamp(0.0, 1.0)),
+                egui::ecolor::gamma_u8_from_linear_f32(b.clamp(0.0, 1.0)),
+                egui::ecolor::linear_u8_from_linear_f32(a.clamp(0.0, 1.0)),
+            ]
+        })
+        .collect()
+}
+
+fn f32_from_f16(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 == 0 { 1.0 } else { -1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        // subnormal
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
 pub fn into_bind_group(
     device: &wgpu::Device,
     layout: &wgpu::BindGroupLayout,
     texture: &wgpu::Texture,
     sampler: &wgpu::Sampler,
+    locals: &wgpu::Buffer,
     label: Option<&str>) -> wgpu::BindGroup
 {
     device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -126,6 +476,10 @@ pub fn into_bind_group(
                 binding: 1,
                 resource: wgpu::BindingResource::Sampler(sampler),
             },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: locals.as_entire_binding(),
+            },
         ],
     })
 }
@@ -133,11 +487,81 @@ pub fn into_bind_group(
 pub fn update_bind_groups<'a>(
     device: &wgpu::Device,
     layout: &wgpu::BindGroupLayout,
-    textures: impl Iterator<Item = (egui::TextureId, wgpu::Texture, &'a wgpu::Sampler)>,
+    textures: impl IntoIterator<Item = (egui::TextureId, wgpu::Texture, &'a wgpu::Sampler, egui::TextureOptions, wgpu::Extent3d)>,
     cache: &mut HashMap<egui::TextureId, TextureResource>)
 {
-    for (id, texture, sampler) in textures {
-        let bind_group = into_bind_group(device, layout, &texture, sampler, Some(&format!("bind-group/id: {id:?}")));
-        cache.insert(id, TextureResource { texture, bind_group });
+    for (id, texture, sampler, options, image_size) in textures {
+        let locals = into_texture_locals(device, texture.size(), image_size);
+        let bind_group = into_bind_group(device, layout, &texture, sampler, &locals, Some(&format!("bind-group/id: {id:?}")));
+        cache.insert(id, TextureResource { texture, bind_group, options, locals, image_size });
+    }
+}
+
+/// Keeps every image up to date on the CPU side, applying partial updates to the whole image
+pub fn backup_images(images: &[(egui::TextureId, egui::epaint::ImageDelta)], backups: &mut HashMap<egui::TextureId, egui::epaint::ImageDelta>) {
+    for (id, img) in images {
+        let Some(pos) = img.pos else {
+            backups.insert(*id, img.clone());
+            continue;
+        };
+        let Some(backup) = backups.get_mut(id) else { continue };
+
+        let (egui::ImageData::Color(dest), egui::ImageData::Color(src)) = (&mut backup.image, &img.image);
+        if (pos[0] + src.width() > dest.width()) || (pos[1] + src.height() > dest.height()) {
+            log::warn!("partial update is out of the image bounds (id: {id:?}), backup skipped");
+            continue;
+        }
+        let dest = Arc::make_mut(dest);
+        let dest_width = dest.width();
+        for (y, row) in src.pixels.chunks_exact(src.width()).enumerate() {
+            let start = (pos[1] + y) * dest_width + pos[0];
+            dest.pixels[start..start + row.len()].copy_from_slice(row);
+        }
+        backup.options = img.options;
+    }
+}
+
+/// Must be called after the frame is submitted: egui may free a texture in the same delta that uploads it.
+pub fn release_textures(
+    ids: &[egui::TextureId],
+    cache: &mut HashMap<egui::TextureId, TextureResource>,
+    backups: &mut HashMap<egui::TextureId, egui::epaint::ImageDelta>)
+{
+    for id in ids {
+        cache.remove(id);
+        backups.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u32 = 8192;
+
+    fn extent(width: u32, height: u32) -> wgpu::Extent3d {
+        wgpu::Extent3d { width, height, depth_or_array_layers: 1 }
+    }
+
+    #[test]
+    fn font_atlas_grows_with_headroom() {
+        let font_atlas = egui::TextureId::Managed(0);
+        assert_eq!(allocation_size(font_atlas, extent(2048, 128), Some(extent(2048, 64)), 1, egui::TextureOptions::LINEAR, MAX), extent(2048, 512));
+        // capped by the device
+        assert_eq!(allocation_size(font_atlas, extent(2048, 4096), Some(extent(2048, 2048)), 1, egui::TextureOptions::LINEAR, MAX), extent(2048, MAX));
+    }
+
+    #[test]
+    fn user_texture_resized_by_a_pixel_keeps_its_size() {
+        let image = egui::TextureId::User(1);
+        assert_eq!(allocation_size(image, extent(2049, 2049), Some(extent(2048, 2048)), 1, egui::TextureOptions::LINEAR, MAX), extent(2049, 2049));
+        // other managed textures, e.g. from `Context::load_texture`
+        let loaded = egui::TextureId::Managed(1);
+        assert_eq!(allocation_size(loaded, extent(2049, 2048), Some(extent(2048, 2048)), 1, egui::TextureOptions::LINEAR, MAX), extent(2049, 2048));
+    }
+
+    #[test]
+    fn first_upload_has_no_headroom() {
+        assert_eq!(allocation_size(egui::TextureId::Managed(0), extent(2048, 64), None, 1, egui::TextureOptions::LINEAR, MAX), extent(2048, 64));
     }
 }
@@ -0,0 +1,101 @@
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+/// Measures how long the GPU spends in the render pass with timestamp queries.
+/// The result is read back without stalling, so it belongs to an earlier frame than the one just rendered.
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    /// nanoseconds per timestamp tick
+    period: f32,
+    /// the readback buffer is being mapped, so the queries cannot be resolved into it
+    pending: bool,
+    mapped: Arc<AtomicBool>,
+    gpu_time_ms: Option<f32>,
+}
+impl GpuTimer {
+    const QUERY_COUNT: u32 = 2;
+    const BUFFER_SIZE: u64 = Self::QUERY_COUNT as u64 * wgpu::QUERY_SIZE as u64;
+
+    /// `None` when the device was created without `TIMESTAMP_QUERY`
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) { return None };
+
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("Timestamp query set"),
+            ty: wgpu::QueryType::Timestamp,
+            count: Self::QUERY_COUNT,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp resolve buffer"),
+            size: Self::BUFFER_SIZE,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Timestamp readback buffer"),
+            size: Self::BUFFER_SIZE,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Some(Self {
+            query_set,
+            resolve_buffer,
+            readback_buffer,
+            period: queue.get_timestamp_period(),
+            pending: false,
+            mapped: Arc::new(AtomicBool::new(false)),
+            gpu_time_ms: None,
+        })
+    }
+
+    /// Picks up a finished readback; returns whether this frame can be measured
+    pub fn poll(&mut self, device: &wgpu::Device) -> bool {
+        if !self.pending { return true };
+
+        let _ = device.poll(wgpu::PollType::Poll);
+        if !self.mapped.swap(false, Ordering::Acquire) { return false };
+
+        {
+            let data = self.readback_buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&data);
+            // 0 for both when the pass was skipped
+            let ticks = timestamps[1].saturating_sub(timestamps[0]);
+            self.gpu_time_ms = Some(ticks as f32 * self.period / 1_000_000.0);
+        }
+        self.readback_buffer.unmap();
+        self.pending = false;
+        true
+    }
+
+    pub fn timestamp_writes(&self) -> wgpu::RenderPassTimestampWrites<'_> {
+        wgpu::RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(0),
+            end_of_pass_write_index: Some(1),
+        }
+    }
+
+    /// Copies the timestamps of the pass into the readback buffer (encode after the pass)
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.resolve_query_set(&self.query_set, 0..Self::QUERY_COUNT, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &self.readback_buffer, 0, Self::BUFFER_SIZE);
+    }
+
+    /// Starts mapping the readback buffer (call after the resolve was submitted)
+    pub fn start_readback(&mut self) {
+        let mapped = Arc::clone(&self.mapped);
+        self.readback_buffer.map_async(wgpu::MapMode::Read, .., move |result| {
+            if result.is_ok() {
+                mapped.store(true, Ordering::Release);
+            }
+        });
+        self.pending = true;
+    }
+
+    /// GPU time of the last measured frame
+    pub fn gpu_time_ms(&self) -> Option<f32> {
+        self.gpu_time_ms
+    }
+}
@@ -0,0 +1,73 @@
+//! egui context behavior the app relies on, checked without a GPU or a window
+
+const SIZE: egui::Vec2 = egui::vec2(200.0, 100.0);
+
+fn input(events: Vec<egui::Event>) -> egui::RawInput {
+    egui::RawInput {
+        screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, SIZE)),
+        events,
+        ..Default::default()
+    }
+}
+
+fn key_press(key: egui::Key) -> Vec<egui::Event> {
+    vec![
+        egui::Event::Key { key, physical_key: Some(key), pressed: true, repeat: false, modifiers: egui::Modifiers::NONE },
+        egui::Event::Key { key, physical_key: Some(key), pressed: false, repeat: false, modifiers: egui::Modifiers::NONE },
+    ]
+}
+
+/// Tab reaches egui unconsumed (see `App::forward_window_event`), so it walks the keyboard focus through the widgets
+#[test]
+fn tab_moves_focus_between_buttons() {
+    let ctx = egui::Context::default();
+    // returns the ids of the buttons
+    let run = |events| {
+        let mut ids = [egui::Id::NULL; 2];
+        let _ = ctx.run(input(events), |cx| {
+            egui::CentralPanel::default().show(cx, |ui| {
+                ids = [ui.button("First").id, ui.button("Second").id];
+            });
+        });
+        ids
+    };
+
+    let ids = run(Vec::new());
+    assert_eq!(ctx.memory(|m| m.focused()), None);
+
+    run(key_press(egui::Key::Tab));
+    run(Vec::new());
+    assert_eq!(ctx.memory(|m| m.focused()), Some(ids[0]));
+
+    run(key_press(egui::Key::Tab));
+    run(Vec::new());
+    assert_eq!(ctx.memory(|m| m.focused()), Some(ids[1]));
+}
+
+/// The reactive run mode sleeps on `repaint_delay`: an animated widget keeps it at zero, an idle UI leaves it at MAX
+#[test]
+fn spinner_requests_continuous_repaints() {
+    let ctx = egui::Context::default();
+    let repaint_delay = |spinning: bool| {
+        let output = ctx.run(input(Vec::new()), |cx| {
+            egui::CentralPanel::default().show(cx, |ui| {
+                ui.label("Idle");
+                if spinning {
+                    ui.spinner();
+                }
+            });
+        });
+        output.viewport_output[&egui::ViewportId::ROOT].repaint_delay
+    };
+
+    // the first passes may ask for another one to settle the layout
+    repaint_delay(false);
+    repaint_delay(false);
+    assert_eq!(repaint_delay(false), std::time::Duration::MAX);
+
+    assert_eq!(repaint_delay(true), std::time::Duration::ZERO);
+    assert_eq!(repaint_delay(true), std::time::Duration::ZERO);
+
+    repaint_delay(false);
+    assert_eq!(repaint_delay(false), std::time::Duration::MAX);
+}
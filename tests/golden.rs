@@ -0,0 +1,436 @@
+//! Renders a fixed UI offscreen and compares it with the PNGs in `tests/golden`.
+//!
+//! Run with `UPDATE_GOLDENS=1` to regenerate the goldens after an intended change.
+//! Falls back to the GL backend (which the goldens were made with) when the primary backends have no adapter.
+//! Fails without any adapter, unless `SKIP_GPU_TESTS=1` is set.
+
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}};
+
+use egui_demo::render::{self, RendererError};
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+/// Largest per-channel difference accepted for a pixel (rasterizers differ slightly on edges)
+const MAX_CHANNEL_DIFF: u8 = 8;
+/// Share of pixels allowed beyond `MAX_CHANNEL_DIFF`, e.g. for anti-aliased glyph edges
+const MAX_MISMATCH_RATIO: f64 = 0.005;
+
+#[test]
+fn widgets() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let ctx = egui::Context::default();
+    ctx.set_theme(egui::Theme::Dark);
+    let mut checker = None;
+    let ui = |cx: &egui::Context| {
+        // nearest-filtered 2x2 texture, so that texture binding and sampler selection show up in the image
+        let checker = checker.get_or_insert_with(|| cx.load_texture(
+            "checker",
+            egui::ColorImage::new([2, 2], vec![egui::Color32::WHITE, egui::Color32::BLUE, egui::Color32::BLUE, egui::Color32::WHITE]),
+            egui::TextureOptions::NEAREST,
+        ));
+        egui::CentralPanel::default().show(cx, |ui| {
+            let _ = ui.button("Button");
+            ui.label("Label");
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(40.0, 20.0), egui::Sense::hover());
+            ui.painter().rect_filled(rect, 4.0, egui::Color32::from_rgb(200, 60, 30));
+            ui.image((checker.id(), egui::vec2(32.0, 32.0)));
+        });
+    };
+
+    let pixels = render_frames(&mut renderer, &ctx, ui);
+    compare_with_golden("widgets", &pixels);
+}
+
+/// Consecutive meshes with the same clip rect and texture are drawn in one call, with the same pixels as one call each
+#[test]
+fn batched_labels() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let ctx = egui::Context::default();
+    ctx.set_theme(egui::Theme::Dark);
+    let ui = |cx: &egui::Context| {
+        egui::CentralPanel::default().show(cx, |ui| {
+            ui.horizontal_wrapped(|ui| {
+                for i in 0..40 {
+                    ui.small(format!("#{i}"));
+                }
+            });
+        });
+    };
+    // uploads the font atlas
+    render_frames(&mut renderer, &ctx, ui);
+
+    // egui already merges the shapes of a clip rect into one mesh, so tessellate them one by one
+    let output = ctx.run(egui::RawInput { screen_rect: Some(screen_rect()), ..Default::default() }, ui);
+    let triangles = output.shapes.into_iter()
+        .flat_map(|shape| ctx.tessellate(vec![shape], output.pixels_per_point))
+        .collect::<Vec<_>>()
+    ;
+    let target = make_target(&renderer);
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+    let batched = renderer.render_to_view(&view, &screen(), &triangles, &output.textures_delta);
+    let batched_pixels = renderer.read_texture(&target).expect("failed to read the frame back");
+    compare_with_golden("labels", &batched_pixels);
+
+    // clip rects that differ, but all cover the frame, keep every mesh in a draw call of its own
+    let unbatched_triangles = triangles.iter().enumerate()
+        .map(|(i, primitive)| egui::ClippedPrimitive { clip_rect: screen_rect().expand(1.0 + i as f32), ..primitive.clone() })
+        .collect::<Vec<_>>()
+    ;
+    let unbatched = renderer.render_to_view(&view, &screen(), &unbatched_triangles, &egui::TexturesDelta::default());
+    let unbatched_pixels = renderer.read_texture(&target).expect("failed to read the frame back");
+
+    assert_eq!(unbatched.draw_calls as usize, triangles.len());
+    assert!(batched.draw_calls < unbatched.draw_calls, "{} draw calls for {} meshes", batched.draw_calls, triangles.len());
+    assert!(batched_pixels == unbatched_pixels, "batching changed the image");
+}
+
+/// A partial update (`ImageDelta::pos`) writes into the existing texture instead of replacing it (and its bind group)
+#[test]
+fn partial_texture_update_keeps_the_texture() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let id = egui::TextureId::User(1);
+    let red = egui::ColorImage::new([4, 4], vec![egui::Color32::RED; 16]);
+    let blue = egui::ColorImage::new([2, 2], vec![egui::Color32::BLUE; 4]);
+    let triangles = [textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32)))];
+    let target = make_target(&renderer);
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let whole = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(red, egui::TextureOptions::NEAREST))], free: vec![] };
+    let stats = renderer.render_to_view(&view, &screen(), &triangles, &whole);
+    assert_eq!(stats.textures_allocated, 1);
+
+    let partial = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::partial([0, 0], blue, egui::TextureOptions::NEAREST))], free: vec![] };
+    let stats = renderer.render_to_view(&view, &screen(), &triangles, &partial);
+    assert_eq!((stats.textures_uploaded, stats.textures_allocated), (1, 0), "the texture was recreated");
+
+    // the top left quarter of the image was replaced, the rest kept
+    let pixels = renderer.read_texture(&target).expect("failed to read the frame back");
+    assert_eq!(pixel(&pixels, WIDTH / 4, HEIGHT / 4), [0, 0, 255, 255]);
+    assert_eq!(pixel(&pixels, WIDTH * 3 / 4, HEIGHT * 3 / 4), [255, 0, 0, 255]);
+}
+
+/// Textures dropped by `clear_textures` come back from their CPU copies once a frame draws them, and only then
+#[test]
+fn cleared_textures_are_restored_when_drawn() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let id = egui::TextureId::User(1);
+    let images = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(egui::ColorImage::new([1, 1], vec![egui::Color32::RED]), egui::TextureOptions::NEAREST))], free: vec![] };
+    let triangles = [textured_quad(id, screen_rect())];
+    let target = make_target(&renderer);
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+    renderer.render_to_view(&view, &screen(), &triangles, &images);
+
+    renderer.clear_textures();
+    let stats = renderer.render_to_view(&view, &screen(), &[], &egui::TexturesDelta::default());
+    assert_eq!(stats.textures_allocated, 0, "a texture that is not drawn was restored");
+    let stats = renderer.render_to_view(&view, &screen(), &triangles, &egui::TexturesDelta::default());
+    assert_eq!(stats.textures_allocated, 1);
+    let stats = renderer.render_to_view(&view, &screen(), &triangles, &egui::TexturesDelta::default());
+    assert_eq!(stats.textures_allocated, 0);
+
+    let pixels = renderer.read_texture(&target).expect("failed to read the frame back");
+    assert_eq!(pixel(&pixels, WIDTH / 2, HEIGHT / 2), egui::Color32::RED.to_array());
+}
+
+/// `TextureOptions::NEAREST` magnifies without blending neighboring texels
+#[test]
+fn nearest_sampling_keeps_hard_edges() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let id = egui::TextureId::User(1);
+    let (red, blue) = (egui::Color32::RED, egui::Color32::BLUE);
+    let image = egui::ColorImage::new([2, 2], vec![red, blue, blue, red]);
+    let images = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(image, egui::TextureOptions::NEAREST))], free: vec![] };
+    let triangles = [textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32)))];
+
+    let texture = renderer.render_to_texture(&screen(), &triangles, &images);
+    let pixels = renderer.read_texture(&texture).expect("failed to read the frame back");
+
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let expected = match (x < WIDTH / 2) == (y < HEIGHT / 2) {
+                true => red,
+                false => blue,
+            };
+            assert_eq!(pixel(&pixels, x, y), expected.to_array(), "at ({x}, {y})");
+        }
+    }
+}
+
+/// A grown image written into a texture with headroom is filtered as if it filled the texture:
+/// LINEAR sampling at its right and bottom edge doesn't blend in the unused texels after it
+#[test]
+fn linear_sampling_stays_inside_a_grown_image() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    // the font atlas, the only texture given headroom
+    let id = egui::TextureId::Managed(0);
+    let upload = |size: usize| {
+        let image = egui::ColorImage::new([size, size], vec![egui::Color32::RED; size * size]);
+        egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(image, egui::TextureOptions::LINEAR))], free: vec![] }
+    };
+    let triangles = [textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32)))];
+
+    drop(renderer.render_to_texture(&screen(), &triangles, &upload(2)));
+    // outgrows the first texture, so the new one gets headroom
+    let texture = renderer.render_to_texture(&screen(), &triangles, &upload(4));
+    let pixels = renderer.read_texture(&texture).expect("failed to read the frame back");
+
+    for (x, y) in [(0, 0), (WIDTH - 1, 0), (0, HEIGHT - 1), (WIDTH - 1, HEIGHT - 1)] {
+        assert_eq!(pixel(&pixels, x, y), egui::Color32::RED.to_array(), "at ({x}, {y})");
+    }
+}
+
+/// A `Callback` draws with its own pipeline, limited to its clip rect
+#[test]
+fn paint_callback_fills_its_clip_rect() {
+    let Some(mut renderer) = create_renderer() else { return };
+
+    let id = egui::TextureId::User(1);
+    let images = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(egui::ColorImage::new([1, 1], vec![egui::Color32::RED]), egui::TextureOptions::NEAREST))], free: vec![] };
+    let format = renderer.surface_format();
+    // a triangle covering the whole frame, cut down to the clip rect by the scissor
+    let fill = move |device: &wgpu::Device, _queue: &wgpu::Queue, pass: &mut wgpu::RenderPass<'_>, _info: &render::CallbackInfo| {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Fill shader"),
+            source: wgpu::ShaderSource::Wgsl(r"
+                @vertex
+                fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+                }
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return vec4<f32>(0.0, 1.0, 0.0, 1.0);
+                }
+            ".into()),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Fill pipeline"),
+            layout: None,
+            vertex: wgpu::VertexState { module: &shader, entry_point: Some("vs_main"), compilation_options: Default::default(), buffers: &[] },
+            fragment: Some(wgpu::FragmentState { module: &shader, entry_point: Some("fs_main"), compilation_options: Default::default(), targets: &[Some(format.into())] }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview_mask: None,
+            cache: None,
+        });
+        pass.set_pipeline(&pipeline);
+        pass.draw(0..3, 0..1);
+    };
+    let clip_rect = egui::Rect::from_min_max(egui::pos2(40.0, 30.0), egui::pos2(120.0, 90.0));
+    let triangles = [
+        textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32))),
+        egui::ClippedPrimitive { clip_rect, primitive: egui::epaint::Primitive::Callback(render::Callback::new_paint_callback(clip_rect, fill)) },
+    ];
+
+    let texture = renderer.render_to_texture(&screen(), &triangles, &images);
+    let pixels = renderer.read_texture(&texture).expect("failed to read the frame back");
+
+    let (red, green) = (egui::Color32::RED.to_array(), egui::Color32::GREEN.to_array());
+    for (x, y) in [(40, 30), (119, 89), (80, 60)] {
+        assert_eq!(pixel(&pixels, x, y), green, "inside at ({x}, {y})");
+    }
+    for (x, y) in [(39, 60), (120, 60), (80, 29), (80, 90), (0, 0), (WIDTH - 1, HEIGHT - 1)] {
+        assert_eq!(pixel(&pixels, x, y), red, "outside at ({x}, {y})");
+    }
+}
+
+/// Resizing replaces the MSAA, depth and supersampled textures instead of piling them up,
+/// and large uploads keep reusing the same few staging buffers
+#[test]
+fn resizing_does_not_leak() {
+    let Some(mut renderer) = create_renderer_with(|builder| builder
+        .sample_count(4)
+        .render_scale(2.0)
+        .depth_format(Some(wgpu::TextureFormat::Depth32Float))
+    ) else { return };
+
+    let id = egui::TextureId::User(1);
+    // large enough to go through the staging pool
+    let video_frame = egui::ColorImage::new([512, 512], vec![egui::Color32::GREEN; 512 * 512]);
+    let frame = |renderer: &mut render::WgpuRenderer, width: u32, height: u32| {
+        let screen = render::ScreenDescriptor { pixel_per_point: 1.0, screen_width: width, screen_height: height };
+        renderer.request_resize(&screen);
+        let images = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(video_frame.clone(), egui::TextureOptions::LINEAR))], free: vec![] };
+        let triangles = [textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(width as f32, height as f32)))];
+        drop(renderer.render_to_texture(&screen, &triangles, &images));
+        renderer.device().poll(wgpu::PollType::wait_indefinitely()).expect("failed to wait for the GPU");
+    };
+    let counts = |renderer: &render::WgpuRenderer| {
+        let counters = renderer.device().get_internal_counters().hal;
+        (counters.textures.read(), counters.buffers.read())
+    };
+
+    for _ in 0..3 {
+        frame(&mut renderer, WIDTH, HEIGHT);
+    }
+    let (textures, buffers) = counts(&renderer);
+
+    for i in 0..60 {
+        frame(&mut renderer, WIDTH + i * 7 % 90, HEIGHT + i * 13 % 70);
+    }
+    frame(&mut renderer, WIDTH, HEIGHT);
+    let (textures_after, buffers_after) = counts(&renderer);
+
+    assert!(textures_after <= textures, "textures grew from {textures} to {textures_after}");
+    assert!(buffers_after <= buffers, "buffers grew from {buffers} to {buffers_after}");
+}
+
+/// A float (HDR) frame reads back as the same sRGB-encoded RGBA8 an 8-bit sRGB frame does
+#[test]
+fn float_frame_reads_back_as_rgba8() {
+    let Some(mut renderer) = create_renderer() else { return };
+    let mut hdr_renderer = render::WgpuRenderer::from_existing(renderer.device().clone(), renderer.queue().clone(), wgpu::TextureFormat::Rgba16Float);
+
+    let id = egui::TextureId::User(1);
+    let color = egui::Color32::from_rgb(200, 60, 30);
+    let images = egui::TexturesDelta { set: vec![(id, egui::epaint::ImageDelta::full(egui::ColorImage::new([1, 1], vec![color]), egui::TextureOptions::NEAREST))], free: vec![] };
+    let triangles = [textured_quad(id, egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32)))];
+
+    let sdr = renderer.render_to_texture(&screen(), &triangles, &images);
+    let hdr = hdr_renderer.render_to_texture(&screen(), &triangles, &images);
+    assert_eq!(hdr.format(), wgpu::TextureFormat::Rgba16Float);
+
+    let sdr = renderer.read_texture(&sdr).expect("failed to read the frame back");
+    let hdr = hdr_renderer.read_texture(&hdr).expect("failed to read the HDR frame back");
+    assert_eq!(hdr.len(), sdr.len());
+    for (x, y) in [(0, 0), (WIDTH / 2, HEIGHT / 2), (WIDTH - 1, HEIGHT - 1)] {
+        let (expected, actual) = (pixel(&sdr, x, y), pixel(&hdr, x, y));
+        assert!(expected.iter().zip(actual).all(|(e, a)| e.abs_diff(a) <= 1), "at ({x}, {y}): {actual:?}, expected {expected:?}");
+    }
+    assert_eq!(pixel(&hdr, 0, 0)[..3], color.to_array()[..3]);
+}
+
+fn screen_rect() -> egui::Rect {
+    egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32))
+}
+
+fn screen() -> render::ScreenDescriptor {
+    render::ScreenDescriptor { pixel_per_point: 1.0, screen_width: WIDTH, screen_height: HEIGHT }
+}
+
+/// Texture of the frame size to draw into with `render_to_view`
+fn make_target(renderer: &render::WgpuRenderer) -> wgpu::Texture {
+    renderer.device().create_texture(&wgpu::TextureDescriptor {
+        label: Some("Test target"),
+        size: wgpu::Extent3d { width: WIDTH, height: HEIGHT, depth_or_array_layers: 1 },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: renderer.surface_format(),
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+/// `rect` (in points) showing the whole texture `id`
+fn textured_quad(id: egui::TextureId, rect: egui::Rect) -> egui::ClippedPrimitive {
+    let mut mesh = egui::Mesh::with_texture(id);
+    mesh.add_rect_with_uv(rect, egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0)), egui::Color32::WHITE);
+    egui::ClippedPrimitive { clip_rect: egui::Rect::EVERYTHING, primitive: egui::epaint::Primitive::Mesh(mesh) }
+}
+
+/// RGBA8 of the pixel at (x, y)
+fn pixel(pixels: &[u8], x: u32, y: u32) -> [u8; 4] {
+    let i = ((y * WIDTH + x) * 4) as usize;
+    pixels[i..i + 4].try_into().unwrap()
+}
+
+fn create_renderer() -> Option<render::WgpuRenderer> {
+    create_renderer_with(|builder| builder)
+}
+
+/// `configure` adjusts the builder, e.g. for MSAA
+fn create_renderer_with(configure: impl Fn(render::WgpuRendererBuilder) -> render::WgpuRendererBuilder) -> Option<render::WgpuRenderer> {
+    let build = |backends: Option<wgpu::Backends>| {
+        let builder = configure(render::WgpuRenderer::builder(WIDTH, HEIGHT));
+        let builder = match backends {
+            Some(backends) => builder.backends(backends),
+            None => builder,
+        };
+        pollster::block_on(builder.build_headless())
+    };
+
+    let err = match build(None) {
+        Ok(renderer) => return Some(renderer),
+        // e.g. CI machines without Vulkan, but with a software GL driver
+        Err(RendererError::AdapterNotFound(_)) => match build(Some(wgpu::Backends::GL)) {
+            Ok(renderer) => return Some(renderer),
+            Err(RendererError::AdapterNotFound(err)) => err,
+            Err(err) => panic!("failed to create a renderer: {err}"),
+        },
+        Err(err) => panic!("failed to create a renderer: {err}"),
+    };
+    if std::env::var_os("SKIP_GPU_TESTS").is_some() {
+        eprintln!("skipped: no adapter available ({err})");
+        return None;
+    }
+    panic!("no adapter available ({err}); set SKIP_GPU_TESTS=1 to skip the GPU tests");
+}
+
+/// Runs two passes (the first one only sizes some widgets) and returns the RGBA8 pixels of the second
+fn render_frames(renderer: &mut render::WgpuRenderer, ctx: &egui::Context, mut ui: impl FnMut(&egui::Context)) -> Vec<u8> {
+    let screen = render::ScreenDescriptor { pixel_per_point: 1.0, screen_width: WIDTH, screen_height: HEIGHT };
+    let input = || egui::RawInput {
+        screen_rect: Some(egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(WIDTH as f32, HEIGHT as f32))),
+        ..Default::default()
+    };
+
+    let mut pixels = Vec::new();
+    for _ in 0..2 {
+        let output = ctx.run(input(), &mut ui);
+        let triangles = ctx.tessellate(output.shapes, output.pixels_per_point);
+        let texture = renderer.render_to_texture(&screen, &triangles, &output.textures_delta);
+        pixels = renderer.read_texture(&texture).expect("failed to read the frame back");
+    }
+    pixels
+}
+
+fn compare_with_golden(name: &str, pixels: &[u8]) {
+    let golden_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{name}.png"));
+    if std::env::var_os("UPDATE_GOLDENS").is_some() {
+        write_png(&golden_path, pixels);
+        return;
+    }
+
+    let golden = read_png(&golden_path);
+    assert_eq!(golden.len(), pixels.len(), "size of {} differs", golden_path.display());
+
+    let mismatched = golden.chunks_exact(4).zip(pixels.chunks_exact(4))
+        .filter(|(expected, actual)| expected.iter().zip(actual.iter()).any(|(e, a)| e.abs_diff(*a) > MAX_CHANNEL_DIFF))
+        .count()
+    ;
+    let ratio = mismatched as f64 / (WIDTH * HEIGHT) as f64;
+    if ratio > MAX_MISMATCH_RATIO {
+        let actual_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{name}.actual.png"));
+        write_png(&actual_path, pixels);
+        panic!(
+            "{mismatched} pixels differ from {} (actual image: {}); rerun with UPDATE_GOLDENS=1 if the change is intended",
+            golden_path.display(), actual_path.display(),
+        );
+    }
+}
+
+fn read_png(path: &Path) -> Vec<u8> {
+    let file = File::open(path).unwrap_or_else(|err| panic!("failed to open {} ({err}); run with UPDATE_GOLDENS=1 to create it", path.display()));
+    let mut reader = png::Decoder::new(std::io::BufReader::new(file)).read_info().expect("invalid PNG");
+    let mut pixels = vec![0; reader.output_buffer_size().expect("PNG too large")];
+    let info = reader.next_frame(&mut pixels).expect("invalid PNG");
+    assert_eq!((info.width, info.height, info.color_type), (WIDTH, HEIGHT, png::ColorType::Rgba), "unexpected format of {}", path.display());
+    pixels.truncate(info.buffer_size());
+    pixels
+}
+
+fn write_png(path: &Path, pixels: &[u8]) {
+    std::fs::create_dir_all(path.parent().expect("no parent directory")).expect("failed to create the directory");
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path).expect("failed to create the PNG")), WIDTH, HEIGHT);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().and_then(|mut writer| writer.write_image_data(pixels)).expect("failed to write the PNG");
+}